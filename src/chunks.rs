@@ -0,0 +1,205 @@
+use crate::hasher::*;
+use crate::merkle::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use std::io;
+use std::io::Read;
+
+/// Files are split into chunks of this size, the last one may be shorter.
+pub const CHUNK_SIZE: usize = 1 << 20;
+
+/// The number of chunks of a file of the given size.
+/// An empty file is a single empty chunk.
+pub fn chunk_count(file_size: u64) -> usize {
+    (file_size.div_ceil(CHUNK_SIZE as u64) as usize).max(1)
+}
+
+// Call f with every chunk read from the reader, in order.
+fn for_each_chunk(reader: &mut dyn Read, mut f: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut read_chunk = |chunk: &mut Vec<u8>| {
+        chunk.clear();
+        (&mut *reader).take(CHUNK_SIZE as u64).read_to_end(chunk)
+    };
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    let mut next = Vec::with_capacity(CHUNK_SIZE);
+    read_chunk(&mut chunk)?;
+    // a chunk is only known to be the last one when nothing follows it,
+    // so a file that ends at a chunk boundary has no empty chunk after it
+    while read_chunk(&mut next)? > 0 {
+        f(&chunk);
+        std::mem::swap(&mut chunk, &mut next);
+    }
+    f(&chunk);
+    Ok(())
+}
+
+/// Build the tree of the chunks of a file read from the reader.
+/// Its root is the leaf of the file, see [`hash_file_reader`].
+pub fn chunk_tree<H: MerkleHasher>(
+    hasher: H,
+    mode: TreeMode,
+    reader: &mut dyn Read,
+) -> io::Result<MerkleTree<H>> {
+    let mut hashes = Vec::new();
+    for_each_chunk(reader, |chunk| hashes.push(hasher.hash_leaf(mode, chunk)))?;
+    Ok(MerkleTree::from_hashes(hashes, mode, hasher))
+}
+
+/// Hash a file read from the reader as a leaf: the root of the tree of its chunks.
+/// A file of a single chunk is hashed as a whole, like a leaf of any other data.
+pub fn hash_file_reader<H: MerkleHasher>(
+    hasher: &H,
+    mode: TreeMode,
+    reader: &mut dyn Read,
+) -> io::Result<[u8; 32]> {
+    // only log2(n) chunk hashes are kept in memory, no matter how large the file is
+    let mut builder = MerkleRootBuilder::new(mode, hasher);
+    for_each_chunk(reader, |chunk| builder.push(hasher.hash_leaf(mode, chunk)))?;
+    Ok(builder.finalize().hash)
+}
+
+/// Hash the content of a file in memory as a leaf, see [`hash_file_reader`].
+pub fn hash_file_bytes<H: MerkleHasher>(hasher: &H, mode: TreeMode, bytes: &[u8]) -> [u8; 32] {
+    hash_file_reader(hasher, mode, &mut &bytes[..]).expect("reading from memory can't fail")
+}
+
+/// Hash the contents read from the readers opened for the items as leaves, in the order of the items,
+/// e.g. files that are not stored whole.
+/// With the `parallel` feature the items are hashed on all cores.
+pub fn hash_readers<T: Sync, H: MerkleHasher>(
+    items: &[T],
+    mode: TreeMode,
+    hasher: &H,
+    open: impl Fn(&T) -> io::Result<Box<dyn Read>> + Sync,
+) -> io::Result<Vec<[u8; 32]>> {
+    #[cfg(feature = "parallel")]
+    let items = items.par_iter();
+    #[cfg(not(feature = "parallel"))]
+    let items = items.iter();
+    items
+        .map(|item| hash_file_reader(hasher, mode, &mut open(item)?))
+        .collect()
+}
+
+/// Calculate the leaf of a file from one of its chunks and the merkle proof of the chunk
+/// in the tree of the given number of chunks.
+/// Every chunk but the last must be full, so the chunks are at the offsets they claim.
+pub fn calculate_file_hash_from_chunk<H: MerkleHasher>(
+    hasher: &H,
+    mode: TreeMode,
+    chunk_index: usize,
+    chunk_count: usize,
+    chunk: &[u8],
+    proof: &[[u8; 32]],
+) -> Result<[u8; 32], String> {
+    if chunk.len() > CHUNK_SIZE || (chunk_index + 1 < chunk_count && chunk.len() != CHUNK_SIZE) {
+        return Err(format!(
+            "Chunk {} of {} has {} bytes",
+            chunk_index,
+            chunk_count,
+            chunk.len()
+        ));
+    }
+    calculate_merkle_root_from_proof(
+        hasher,
+        mode,
+        chunk_index,
+        chunk_count,
+        &hasher.hash_leaf(mode, chunk),
+        proof,
+    )
+    .map_err(|e| format!("Invalid proof for chunk {}: {}", chunk_index, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chunks::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // A few chunks and a half, with bytes that differ between chunks.
+    fn large_file() -> Vec<u8> {
+        (0..CHUNK_SIZE * 5 / 2).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn chunk_proofs_lead_to_file_hash() {
+        let bytes = large_file();
+        for mode in [TreeMode::Plain, TreeMode::Rfc6962] {
+            let tree = chunk_tree(Sha256Hasher, mode, &mut &bytes[..]).unwrap();
+            let file_hash = hash_file_bytes(&Sha256Hasher, mode, &bytes);
+            assert_eq!(*tree.get_merkle_root(), file_hash);
+            let chunk_count = chunk_count(bytes.len() as u64);
+            assert_eq!(tree.root().leaf_count, chunk_count);
+            for (index, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+                let proof = tree.make_merkle_proof(index);
+                assert_eq!(
+                    calculate_file_hash_from_chunk(
+                        &Sha256Hasher,
+                        mode,
+                        index,
+                        chunk_count,
+                        chunk,
+                        &proof
+                    ),
+                    Ok(file_hash)
+                );
+            }
+            // a short chunk in the middle would shift the offsets of the ones after it
+            let proof = tree.make_merkle_proof(1);
+            assert!(calculate_file_hash_from_chunk(
+                &Sha256Hasher,
+                mode,
+                1,
+                chunk_count,
+                &bytes[CHUNK_SIZE..CHUNK_SIZE + 10],
+                &proof
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn small_files_are_hashed_whole() {
+        for bytes in [&b""[..], b"file 1", &[7u8; CHUNK_SIZE]] {
+            assert_eq!(
+                hash_file_bytes(&Blake3Hasher, TreeMode::Rfc6962, bytes),
+                Blake3Hasher.hash_leaf(TreeMode::Rfc6962, bytes)
+            );
+        }
+    }
+
+    #[test]
+    fn chunk_boundaries() {
+        assert_eq!(chunk_count(0), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE as u64), 1);
+        assert_eq!(chunk_count(CHUNK_SIZE as u64 + 1), 2);
+        let bytes = vec![1u8; 2 * CHUNK_SIZE];
+        let tree = chunk_tree(Sha256Hasher, TreeMode::Plain, &mut &bytes[..]).unwrap();
+        assert_eq!(tree.root().leaf_count, 2);
+    }
+
+    #[test]
+    fn readers_are_hashed_in_order() {
+        let dir = std::env::temp_dir().join(format!("mermade-files-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let files: Vec<PathBuf> = (0..64)
+            .map(|i| {
+                let path = dir.join(i.to_string());
+                fs::write(&path, vec![i as u8; i * 100]).unwrap();
+                path
+            })
+            .collect();
+        let hashes = hash_readers(&files, TreeMode::Rfc6962, &Sha256Hasher, |file| {
+            Ok(Box::new(fs::File::open(file)?))
+        });
+        let serial: io::Result<Vec<[u8; 32]>> = files
+            .iter()
+            .map(|file| {
+                hash_file_reader(&Sha256Hasher, TreeMode::Rfc6962, &mut fs::File::open(file)?)
+            })
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(hashes.unwrap(), serial.unwrap());
+    }
+}
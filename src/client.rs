@@ -1,38 +1,274 @@
+use crate::chunks::*;
+use crate::dedup::*;
+use crate::entry::*;
+use crate::hasher::*;
+use crate::manifest::Manifest;
 use crate::merkle::*;
+use crate::proof::*;
+use crate::receipt::*;
+use crate::sorted::*;
+use crate::sparse::*;
+use crate::walk::*;
 use indicatif::ProgressBar;
 use reqwest::blocking::multipart;
-use sha2::Digest;
-use sha2::Sha256;
+use std::collections::HashSet;
 use std::io;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
 
-pub fn upload_all_and_delete(server_url: &str, files_dir: &str) {
-    eprintln!("Uploading files from {} to {}...", files_dir, server_url);
-    let files = list_files_in_order(files_dir).unwrap_or_else(|e| {
+/// Which merkle root the upload outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RootKind {
+    /// The tree of the files in the listing order, files are addressed by their index.
+    Indexed,
+    /// The sparse tree of the files keyed by their names, files are addressed by name.
+    Sparse,
+    /// The tree of the files ordered by the keys of their names, to prove that a file wasn't uploaded.
+    Sorted,
+}
+
+/// What the leaves of the uploaded files commit to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeafBinding {
+    /// The content of the file only.
+    Content,
+    /// The entry of the file with its path and size, see [`FileEntry`].
+    Path,
+    /// The entry of the file with its permissions and modification time as well.
+    Metadata,
+}
+
+/// How `upload` builds the tree of the files, sends them and what it outputs.
+pub struct UploadOptions {
+    pub mode: TreeMode,
+    /// The server's first choice if not given.
+    pub algorithm: Option<HashAlgorithm>,
+    pub root_kind: RootKind,
+    /// Send only the chunks the server doesn't have.
+    pub dedup: bool,
+    pub binding: LeafBinding,
+    /// Where to write the manifest of the upload, if anywhere.
+    pub manifest: Option<String>,
+    /// Which files of the directory tree are uploaded.
+    pub walk: WalkOptions,
+    /// What happens to the files after the upload.
+    pub deletion: Deletion,
+    /// The key the server must sign the root with, any key if not given.
+    pub server_key: Option<[u8; 32]>,
+}
+
+/// What happens to the local files once the server has them all and a sample of them was verified.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Deletion {
+    #[default]
+    Delete,
+    /// Verify the sample and list the files that would be deleted.
+    DryRun,
+    /// Leave the files where they are, without verifying a sample.
+    Keep,
+    /// Move the files to the directory, under their paths.
+    Trash(PathBuf),
+}
+
+// The files to upload, exits if the directory can't be walked or the leaves can't bind them.
+fn walk_files(files_dir: &str, options: &UploadOptions, mode: TreeMode) -> Vec<WalkedFile> {
+    if options.binding != LeafBinding::Content {
+        if let Err(e) = check_entry_mode(mode) {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    } else if options.walk.symlinks == SymlinkPolicy::Store {
+        eprintln!("Symlinks can only be stored with --bind-paths or --bind-metadata");
+        process::exit(1);
+    } else if options.manifest.is_some() {
+        // a manifest of content leaves would have paths no hash or signature covers
+        eprintln!("A manifest can only be written with --bind-paths or --bind-metadata");
+        process::exit(1);
+    }
+    walk_dir(files_dir, &options.walk).unwrap_or_else(|e| {
         eprintln!("Failed to read files in {}: {}", files_dir, e);
         process::exit(1);
+    })
+}
+
+pub fn upload_all_and_delete(server_url: &str, files_dir: &str, options: &UploadOptions) {
+    let UploadOptions {
+        mode,
+        algorithm,
+        root_kind,
+        dedup,
+        binding,
+        ..
+    } = *options;
+    let files = walk_files(files_dir, options, mode);
+    eprintln!("Uploading files from {} to {}...", files_dir, server_url);
+    let client = reqwest::blocking::Client::new();
+    let algorithm = negotiate_algorithm(&client, server_url, algorithm).unwrap_or_else(|e| {
+        eprintln!("Failed to agree on a hash algorithm: {}", e);
+        process::exit(1);
     });
+    eprintln!("Using {} {} tree", algorithm, mode);
     eprintln!("Uploading {} files...", files.len());
+    let url = format!(
+        "{}/upload?algorithm={}&mode={}",
+        server_url, algorithm, mode
+    );
+    upload_files(&client, server_url, &url, &files, 0, dedup, binding);
+    eprintln!("Files uploaded!");
+    // this reads the files again, but it's ok for a demo
+    let merkle_root = calculate_merkle_root(&files, mode, algorithm, binding).unwrap_or_else(|e| {
+        eprintln!("Failed to calculate merkle root: {}", e);
+        process::exit(1);
+    });
+    // nothing is kept or deleted unless the server has the same files
+    let receipt = finalize_upload(&client, server_url, options.server_key.as_ref())
+        .and_then(|receipt| receipt.check(&merkle_root).map(|_| receipt))
+        .unwrap_or_else(|e| {
+            eprintln!("The server doesn't have the uploaded files: {}", e);
+            process::exit(1);
+        });
+    let output = match root_kind {
+        RootKind::Indexed => output_merkle_root(&merkle_root),
+        RootKind::Sparse => output_sparse_root(&files, mode, algorithm, binding),
+        RootKind::Sorted => output_sorted_root(&files, mode, algorithm, binding),
+    };
+    if let Err(e) = output {
+        eprintln!("Failed to output merkle root: {}", e);
+        process::exit(1);
+    }
+    if let Some(manifest_path) = &options.manifest {
+        let written = write_manifest(manifest_path, server_url, &files, &receipt, binding);
+        if let Err(e) = written {
+            eprintln!("Failed to write manifest {}: {}", manifest_path, e);
+            process::exit(1);
+        }
+    }
+    delete_files(server_url, files_dir, &files, &receipt, 0, options);
+}
+
+// The entries of the files, with their metadata if it's bound into the leaves.
+fn file_entries(
+    files: &[WalkedFile],
+    mode: TreeMode,
+    algorithm: HashAlgorithm,
+    binding: LeafBinding,
+) -> io::Result<Vec<FileEntry>> {
+    let with_metadata = binding == LeafBinding::Metadata;
+    files
+        .iter()
+        .zip(hash_readers(files, mode, &algorithm, WalkedFile::open)?)
+        .map(|(file, hash)| file.entry(with_metadata, hash))
+        .collect()
+}
+
+// The leaves of the files: the hashes of their contents, or of their entries if they are bound.
+fn file_leaves(
+    files: &[WalkedFile],
+    mode: TreeMode,
+    algorithm: HashAlgorithm,
+    binding: LeafBinding,
+) -> io::Result<Vec<[u8; 32]>> {
+    if binding == LeafBinding::Content {
+        return hash_readers(files, mode, &algorithm, WalkedFile::open);
+    }
+    let entries = file_entries(files, mode, algorithm, binding)?;
+    Ok(entries.iter().map(|entry| entry.leaf(&algorithm)).collect())
+}
+
+// Write the manifest of the uploaded files, with the root of their leaves and the server's receipt of it.
+fn write_manifest(
+    manifest_path: &str,
+    server_url: &str,
+    files: &[WalkedFile],
+    receipt: &Receipt,
+    binding: LeafBinding,
+) -> Result<(), String> {
+    let MerkleRoot {
+        mode, algorithm, ..
+    } = receipt.root;
+    let entries = file_entries(files, mode, algorithm, binding).map_err(|e| e.to_string())?;
+    let manifest = Manifest::new(server_url, entries, receipt.clone());
+    // the receipt was just checked against the pinned key, if there is one
+    manifest.check(&receipt.key)?;
+    manifest.save(manifest_path)?;
+    eprintln!(
+        "Manifest of {} files written to {}",
+        manifest.files.len(),
+        manifest_path
+    );
+    Ok(())
+}
+
+// The query of the entry of a file for the server, which adds the size and the content hash.
+fn entry_query(file: &WalkedFile, binding: LeafBinding) -> io::Result<Vec<(&'static str, String)>> {
+    let mut query = Vec::new();
+    if binding != LeafBinding::Content {
+        query.push(("path", file.path.clone()));
+    }
+    if file.link_target.is_some() {
+        query.push(("symlink", true.to_string()));
+    } else if binding == LeafBinding::Metadata {
+        let (permissions, mtime) = file_metadata(&file.source)?;
+        query.extend(permissions.map(|permissions| ("permissions", permissions.to_string())));
+        query.extend(mtime.map(|mtime| ("mtime", mtime.to_string())));
+    }
+    Ok(query)
+}
+
+// Upload the files one by one, named by their index starting from first_index.
+// The paths of the files are sent along as their names, so that the files can be addressed by name as well.
+// With dedup, only the chunks the server doesn't have are sent, followed by the recipe of the file.
+fn upload_files(
+    client: &reqwest::blocking::Client,
+    server_url: &str,
+    url: &str,
+    files: &[WalkedFile],
+    first_index: usize,
+    dedup: bool,
+    binding: LeafBinding,
+) {
     let bar = ProgressBar::new(files.len() as u64);
-    let client = reqwest::blocking::Client::new();
-    let url = format!("{}/upload", server_url);
-    for (index, file) in files.iter().enumerate() {
-        // TODO: use buffered reader if needed
-        // TODO: read each file only once
-        let file_part = multipart::Part::file(file)
+    let (mut sent_chunks, mut all_chunks) = (0, 0);
+    for (index, file) in (first_index..).zip(files) {
+        let file_part = if dedup {
+            let (recipe, sent) = upload_chunks(client, server_url, file).unwrap_or_else(|e| {
+                eprintln!("Failed to upload chunks of file {}: {}", file.path, e);
+                process::exit(1);
+            });
+            sent_chunks += sent;
+            all_chunks += recipe.chunks.len();
+            Ok(multipart::Part::bytes(recipe.to_string().into_bytes()))
+        } else if let Some(target) = &file.link_target {
+            Ok(multipart::Part::bytes(target.clone().into_bytes()))
+        } else {
+            // TODO: use buffered reader if needed
+            // TODO: read each file only once
+            multipart::Part::file(&file.source)
+        };
+        let file_part = file_part
             .map(|p| p.file_name(index.to_string()))
             .unwrap_or_else(|e| {
-                eprintln!("Failed to read file {}: {}", file.display(), e);
+                eprintln!("Failed to read file {}: {}", file.path, e);
                 process::exit(1);
             });
+        let entry_query = entry_query(file, binding).unwrap_or_else(|e| {
+            eprintln!("Failed to read file {}: {}", file.path, e);
+            process::exit(1);
+        });
         let form = multipart::Form::new().part("file", file_part);
         let response = client
-            .post(&url)
+            .post(url)
+            .query(&[("name", &file.path)])
+            .query(&[("recipe", dedup)])
+            .query(&entry_query)
             .multipart(form)
             .send()
             .unwrap_or_else(|e| {
-                eprintln!("Failed to upload file {}: {}", file.display(), e);
+                eprintln!("Failed to upload file {}: {}", file.path, e);
                 process::exit(1);
             });
         if !response.status().is_success() {
@@ -42,38 +278,405 @@ pub fn upload_all_and_delete(server_url: &str, files_dir: &str) {
         bar.inc(1);
     }
     bar.finish_and_clear();
+    if dedup {
+        eprintln!("Sent {} new chunks of {}", sent_chunks, all_chunks);
+    }
+}
+
+// Send the content-defined chunks of the file the server doesn't have yet.
+// Returns the recipe of the file and the number of chunks sent.
+fn upload_chunks(
+    client: &reqwest::blocking::Client,
+    server_url: &str,
+    file: &WalkedFile,
+) -> Result<(Recipe, usize), String> {
+    let open = || file.open().map_err(|e| e.to_string());
+    let recipe = Recipe::of(open()?)?;
+    let keys: Vec<String> = recipe.chunks.iter().map(|(key, _)| hex_hash(key)).collect();
+    let mut missing: HashSet<String> = client
+        .post(format!("{}/chunks/missing", server_url))
+        .body(keys.join("\n"))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(|e| e.to_string())?
+        .lines()
+        .map(str::to_string)
+        .collect();
+    let mut sent = 0;
+    // the file is read again instead of keeping its chunks in memory
+    for_each_cdc_chunk(open()?, |chunk| {
+        let key = hex_hash(&chunk_key(chunk));
+        // a chunk that is in the file several times is sent once
+        if missing.remove(&key) {
+            client
+                .put(format!("{}/chunks/{}", server_url, key))
+                .body(chunk.to_vec())
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| e.to_string())?;
+            sent += 1;
+        }
+        Ok(())
+    })?;
+    if let Some(key) = missing.iter().next() {
+        return Err(format!(
+            "File changed while uploading, chunk {} is gone",
+            key
+        ));
+    }
+    Ok((recipe, sent))
+}
+
+/// Upload all files in the files_dir directory as new files appended to the dataset
+/// of the merkle root read from stdin, the tree mode and the hash algorithm are the ones of the root.
+/// The server must prove that it kept every old file and added exactly the new ones,
+/// then the new merkle root is written to stdout.
+pub fn append_all_and_delete(server_url: &str, files_dir: &str, options: &UploadOptions) {
+    let old_root = get_merkle_root().unwrap_or_else(|e| {
+        eprintln!("Failed to read merkle root: {}", e);
+        process::exit(1);
+    });
+    let UploadOptions { dedup, binding, .. } = *options;
+    let files = walk_files(files_dir, options, old_root.mode);
+    eprintln!("Appending files from {} to {}...", files_dir, server_url);
+    eprintln!(
+        "Appending {} files to {} files...",
+        files.len(),
+        old_root.leaf_count
+    );
+    let client = reqwest::blocking::Client::new();
+    let url = format!("{}/upload?append=true", server_url);
+    upload_files(
+        &client,
+        server_url,
+        &url,
+        &files,
+        old_root.leaf_count,
+        dedup,
+        binding,
+    );
     eprintln!("Files uploaded!");
-    // this traverses the files again, but it's ok for a demo
-    if let Err(e) = output_merkle_root(files_dir) {
-        eprintln!("Failed to output merkle root: {}", e);
+    let receipt = verify_appended(server_url, &old_root, &files, options).unwrap_or_else(|e| {
+        eprintln!("Append verification failed: {}", e);
         process::exit(1);
+    });
+    delete_files(
+        server_url,
+        files_dir,
+        &files,
+        &receipt,
+        old_root.leaf_count,
+        options,
+    );
+}
+
+// Check that the server's new tree extends the old one with exactly the given files
+// and output the new merkle root, the receipt of which is returned.
+fn verify_appended(
+    server_url: &str,
+    old_root: &MerkleRoot,
+    files: &[WalkedFile],
+    options: &UploadOptions,
+) -> Result<Receipt, String> {
+    let client = reqwest::blocking::Client::new();
+    let receipt = finalize_upload(&client, server_url, options.server_key.as_ref())?;
+    let new_root = receipt.root;
+    let new_leaf_count = old_root.leaf_count + files.len();
+    if receipt.leaf_count != new_leaf_count || new_root.leaf_count != new_leaf_count {
+        return Err(format!(
+            "Server has {} files, expected {}",
+            receipt.leaf_count, new_leaf_count
+        ));
+    }
+    // the old files are untouched
+    let proof_bytes = download_consistency_proof(server_url, old_root.leaf_count)
+        .map_err(|e| format!("Failed to download consistency proof: {}", e))?;
+    verify_consistency(old_root, &new_root, &deserialize_proof(&proof_bytes)?)?;
+    // and the new files are ours
+    if !files.is_empty() {
+        let leaves: Vec<(usize, [u8; 32])> = (old_root.leaf_count..)
+            .zip(
+                file_leaves(files, new_root.mode, new_root.algorithm, options.binding)
+                    .map_err(|e| format!("Failed to read files: {}", e))?,
+            )
+            .collect();
+        let indices: Vec<usize> = (old_root.leaf_count..new_leaf_count).collect();
+        let (proof_bytes, _) = download_multiproof(server_url, &indices)
+            .map_err(|e| format!("Failed to download multiproof: {}", e))?;
+        verify_files(&new_root, &leaves, &deserialize_proof(&proof_bytes)?)?;
     }
-    // delete files
-    delete_files();
+    output_merkle_root(&new_root).map_err(|e| e.to_string())?;
+    Ok(receipt)
 }
 
-fn delete_files() {
-    eprintln!("Deleting files...");
-    // I will not delete any files just in case,
-    // it's a demo anyways.
-    eprintln!("Joking. I'm not deleting anything, it's a demo!");
+/// Pick the hash algorithm for the upload among the ones the server supports.
+/// Without an explicit choice the first algorithm the server lists is used.
+fn negotiate_algorithm(
+    client: &reqwest::blocking::Client,
+    server_url: &str,
+    requested: Option<HashAlgorithm>,
+) -> Result<HashAlgorithm, String> {
+    let url = format!("{}/algorithms", server_url);
+    let response = client.get(url).send().map_err(|e| e.to_string())?;
+    // servers that predate the negotiation only know SHA256
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return match requested {
+            None | Some(HashAlgorithm::Sha256) => Ok(HashAlgorithm::Sha256),
+            Some(algorithm) => Err(format!("Server does not support {}", algorithm)),
+        };
+    }
+    let body = response
+        .error_for_status()
+        .and_then(|r| r.text())
+        .map_err(|e| e.to_string())?;
+    // skip the algorithms this client doesn't know
+    let supported: Vec<HashAlgorithm> = body.lines().filter_map(|l| l.parse().ok()).collect();
+    match requested {
+        Some(algorithm) if supported.contains(&algorithm) => Ok(algorithm),
+        Some(algorithm) => Err(format!("Server does not support {}", algorithm)),
+        None => supported
+            .first()
+            .copied()
+            .ok_or_else(|| "Server supports no known hash algorithm".to_string()),
+    }
 }
 
-fn output_merkle_root(files_dir: &str) -> Result<(), std::io::Error> {
-    let files = list_files_in_order(files_dir)?;
-    let mut hashes: Vec<[u8; 32]> = Vec::with_capacity(files.len());
-    for file in &files {
-        let hash = hash_file_by_path(&file)?;
-        hashes.push(hash);
+// The number of uploaded files downloaded and verified before any file is deleted.
+const DELETE_SAMPLE_SIZE: usize = 16;
+
+// Download a random sample of the uploaded files, the first of which has the index first_index,
+// and verify them against the root calculated from the local files, so that the files are
+// only deleted if the server can give them back.
+fn verify_sample(
+    server_url: &str,
+    receipt: &Receipt,
+    files: &[WalkedFile],
+    first_index: usize,
+    binding: LeafBinding,
+) -> Result<(), String> {
+    let merkle_root = &receipt.root;
+    let sample_size = DELETE_SAMPLE_SIZE.min(files.len());
+    eprintln!("Verifying a sample of {} files...", sample_size);
+    for i in rand::seq::index::sample(&mut rand::thread_rng(), files.len(), sample_size) {
+        let file = &files[i];
+        let entry = match binding {
+            LeafBinding::Content => None,
+            _ => file_entries(
+                std::slice::from_ref(file),
+                merkle_root.mode,
+                merkle_root.algorithm,
+                binding,
+            )
+            .map_err(|e| format!("Failed to read file {}: {}", file.path, e))?
+            .pop(),
+        };
+        download_verified_file(
+            server_url,
+            merkle_root,
+            first_index + i,
+            entry.as_ref(),
+            ProofEncoding::default(),
+            Some(&receipt.key),
+        )
+        .map_err(|e| format!("{}: {}", file.path, e))?;
+    }
+    Ok(())
+}
+
+// Move a file to the trash directory under its path, copying it if it's on another file system.
+fn move_to_trash(file: &WalkedFile, trash_dir: &Path) -> io::Result<()> {
+    let target = trash_dir.join(&file.path);
+    if target.symlink_metadata().is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} is already in the trash", file.path),
+        ));
+    }
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    match std::fs::rename(&file.source, &target) {
+        Ok(()) => Ok(()),
+        Err(_) if file.link_target.is_none() => {
+            std::fs::copy(&file.source, &target)?;
+            std::fs::remove_file(&file.source)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// Check that the trash directory is not among the uploaded files, where a later upload would pick it up.
+fn check_trash_dir(files_dir: &str, trash_dir: &Path) -> io::Result<()> {
+    std::fs::create_dir_all(trash_dir)?;
+    if std::fs::canonicalize(trash_dir)?.starts_with(std::fs::canonicalize(files_dir)?) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("The trash directory can't be inside {}", files_dir),
+        ));
+    }
+    Ok(())
+}
+
+// Delete the uploaded files, or move them to the trash, as the options say.
+// Every file was accepted by the server by now, and nothing is deleted unless a random sample of them
+// downloads and verifies against the merkle root. Files reached through a followed link are kept,
+// deleting them would delete what the link points to. Directories left empty are removed.
+fn delete_files(
+    server_url: &str,
+    files_dir: &str,
+    files: &[WalkedFile],
+    receipt: &Receipt,
+    first_index: usize,
+    options: &UploadOptions,
+) {
+    if options.deletion == Deletion::Keep {
+        eprintln!("Keeping the files in {}", files_dir);
+        return;
+    }
+    if let Deletion::Trash(trash_dir) = &options.deletion {
+        if let Err(e) = check_trash_dir(files_dir, trash_dir) {
+            eprintln!("Not deleting anything: {}", e);
+            process::exit(1);
+        }
+    }
+    if let Err(e) = verify_sample(server_url, receipt, files, first_index, options.binding) {
+        eprintln!(
+            "Not deleting anything, the server failed to give a file back: {}",
+            e
+        );
+        process::exit(1);
+    }
+    let mut removed = 0;
+    let mut failed = 0;
+    let mut dirs = HashSet::new();
+    for file in files {
+        if file.through_link {
+            eprintln!("Keeping {}, it's reached through a symlink", file.path);
+            continue;
+        }
+        let result = match &options.deletion {
+            Deletion::DryRun => {
+                eprintln!("Would delete {}", file.source.display());
+                Ok(())
+            }
+            Deletion::Trash(trash_dir) => move_to_trash(file, trash_dir),
+            _ => std::fs::remove_file(&file.source),
+        };
+        match result {
+            Ok(()) => {
+                removed += 1;
+                let files_dir = Path::new(files_dir);
+                dirs.extend(
+                    file.source
+                        .ancestors()
+                        .skip(1)
+                        .take_while(|dir| *dir != files_dir)
+                        .map(Path::to_path_buf),
+                );
+            }
+            Err(e) => {
+                eprintln!("Failed to delete {}: {}", file.source.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    match &options.deletion {
+        Deletion::DryRun => eprintln!("{} files would be deleted", removed),
+        Deletion::Trash(trash_dir) => {
+            eprintln!("Moved {} files to {}", removed, trash_dir.display())
+        }
+        _ => eprintln!("Deleted {} files", removed),
+    }
+    if options.deletion != Deletion::DryRun {
+        // the deepest first, a directory with files left in it stays
+        let mut dirs: Vec<PathBuf> = dirs.into_iter().collect();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in dirs {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+    if failed > 0 {
+        eprintln!("{} files could not be deleted", failed);
+        process::exit(1);
     }
-    let merkle_tree = MerkleTree::from_hashes(hashes);
+}
+
+// The number of files hashed at once when computing the merkle root, see `hash_readers`.
+const HASH_BATCH_SIZE: usize = 1 << 14;
+
+// The merkle root of the files, the one the server's tree must have.
+fn calculate_merkle_root(
+    files: &[WalkedFile],
+    mode: TreeMode,
+    algorithm: HashAlgorithm,
+    binding: LeafBinding,
+) -> Result<MerkleRoot, std::io::Error> {
+    // only log2(n) hashes and a batch of file hashes are kept in memory, no matter how many files there are
+    let mut builder = MerkleRootBuilder::new(mode, algorithm);
+    for batch in files.chunks(HASH_BATCH_SIZE) {
+        for hash in file_leaves(batch, mode, algorithm, binding)? {
+            builder.push(hash);
+        }
+    }
+    Ok(builder.finalize())
+}
+
+fn output_merkle_root(merkle_root: &MerkleRoot) -> Result<(), std::io::Error> {
     eprintln!(
         "Merkle Root for {} files: {}",
-        &files.len(),
-        hex_hash(merkle_tree.get_merkle_root())
+        merkle_root.leaf_count, merkle_root
     );
     // write string to stdout
-    io::stdout().write_all(hex_hash(merkle_tree.get_merkle_root()).as_bytes())?;
+    io::stdout().write_all(merkle_root.to_string().as_bytes())?;
+    Ok(())
+}
+
+// Output the root of the sparse merkle tree of the files keyed by their names.
+fn output_sparse_root(
+    files: &[WalkedFile],
+    mode: TreeMode,
+    algorithm: HashAlgorithm,
+    binding: LeafBinding,
+) -> Result<(), std::io::Error> {
+    let mut tree = SparseMerkleTree::new(mode, algorithm);
+    for (file, hash) in files
+        .iter()
+        .zip(file_leaves(files, mode, algorithm, binding)?)
+    {
+        tree.insert(name_key(&algorithm, &file.path), hash);
+    }
+    let sparse_root = tree.root();
+    eprintln!(
+        "Sparse Merkle Root for {} files: {}",
+        files.len(),
+        sparse_root
+    );
+    io::stdout().write_all(sparse_root.to_string().as_bytes())?;
+    Ok(())
+}
+
+// Output the root of the merkle tree of the files ordered by the keys of their names.
+fn output_sorted_root(
+    files: &[WalkedFile],
+    mode: TreeMode,
+    algorithm: HashAlgorithm,
+    binding: LeafBinding,
+) -> Result<(), std::io::Error> {
+    let entries = files
+        .iter()
+        .map(|file| name_key(&algorithm, &file.path))
+        .zip(file_leaves(files, mode, algorithm, binding)?)
+        .collect();
+    let sorted_root = SortedMerkleTree::from_entries(entries, mode, algorithm)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        .root();
+    eprintln!(
+        "Sorted Merkle Root for {} files: {}",
+        files.len(),
+        sorted_root
+    );
+    io::stdout().write_all(sorted_root.to_string().as_bytes())?;
     Ok(())
 }
 
@@ -85,73 +688,969 @@ fn download_file(
     reqwest::blocking::get(url)?.error_for_status()?.bytes()
 }
 
+// The receipt of the root a proof leads to, which the server attaches to the proof if it signed the root.
+fn proof_receipt(response: &reqwest::blocking::Response) -> Option<Receipt> {
+    let header = response.headers().get(RECEIPT_HEADER)?;
+    serde_json::from_slice(header.as_bytes()).ok()
+}
+
+// Check that the server signed the merkle root with the pinned key.
+fn check_signed_root(
+    receipt: Option<&Receipt>,
+    merkle_root: &MerkleRoot,
+    server_key: &[u8; 32],
+) -> Result<(), String> {
+    let receipt = receipt.ok_or("The server didn't sign the merkle root")?;
+    receipt.verify(Some(server_key))?;
+    receipt.check(merkle_root)
+}
+
+// The proof is for the root of the first leaf_count files,
+// so roots from before files were appended can still be used.
+// Returns the proof with the encoding the server chose, which may not be the requested one,
+// and the receipt of the root if the server signed it.
 fn download_proof(
     server_url: &str,
     file_index: usize,
+    leaf_count: usize,
+    encoding: ProofEncoding,
+) -> Result<(ProofEncoding, actix_web::web::Bytes, Option<Receipt>), reqwest::Error> {
+    let url = format!("{}/proofs/{}?size={}", server_url, file_index, leaf_count);
+    let response = reqwest::blocking::Client::new()
+        .get(url)
+        .header(reqwest::header::ACCEPT, encoding.content_type())
+        .send()?
+        .error_for_status()?;
+    let encoding = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(ProofEncoding::from_content_type)
+        .unwrap_or_default();
+    let receipt = proof_receipt(&response);
+    Ok((encoding, response.bytes()?, receipt))
+}
+
+fn download_chunk(
+    server_url: &str,
+    file_index: usize,
+    chunk: usize,
+) -> Result<actix_web::web::Bytes, reqwest::Error> {
+    let url = format!("{}/files/{}/chunks/{}", server_url, file_index, chunk);
+    reqwest::blocking::get(url)?.error_for_status()?.bytes()
+}
+
+fn download_chunk_proof(
+    server_url: &str,
+    file_index: usize,
+    chunk: usize,
+) -> Result<Proof, String> {
+    let url = format!("{}/files/{}/chunks/{}/proof", server_url, file_index, chunk);
+    let bytes = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .map_err(|e| e.to_string())?;
+    Proof::decode(&bytes).map_err(|e| e.to_string())
+}
+
+// The entry of a file from the server, `None` if the file was uploaded without a path.
+fn download_entry(request: reqwest::blocking::RequestBuilder) -> Result<Option<FileEntry>, String> {
+    let response = request.send().map_err(|e| e.to_string())?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let bytes = response
+        .error_for_status()
+        .and_then(|response| response.bytes())
+        .map_err(|e| e.to_string())?;
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|e| format!("Invalid entry: {}", e))
+}
+
+fn download_file_entry(server_url: &str, file_index: usize) -> Result<Option<FileEntry>, String> {
+    let url = format!("{}/files/{}/entry", server_url, file_index);
+    download_entry(reqwest::blocking::Client::new().get(url))
+}
+
+// The leaf of a downloaded file with the given content hash: the hash of its entry if it has one.
+// A server can't leave the entry out, the content hash isn't the leaf of a file with an entry.
+fn file_leaf(
+    algorithm: &HashAlgorithm,
+    mode: TreeMode,
+    entry: Option<&FileEntry>,
+    hash: [u8; 32],
+) -> Result<[u8; 32], String> {
+    match entry {
+        Some(entry) => {
+            check_entry_mode(mode)?;
+            entry.check(&hash)?;
+            Ok(entry.leaf(algorithm))
+        }
+        None => Ok(hash),
+    }
+}
+
+// The multiproof of the files, with the receipt of the root if the server signed it.
+fn download_multiproof(
+    server_url: &str,
+    file_indices: &[usize],
+) -> Result<(actix_web::web::Bytes, Option<Receipt>), reqwest::Error> {
+    let indices: Vec<String> = file_indices.iter().map(|i| i.to_string()).collect();
+    let url = format!("{}/multiproof?indices={}", server_url, indices.join(","));
+    let response = reqwest::blocking::get(url)?.error_for_status()?;
+    let receipt = proof_receipt(&response);
+    Ok((response.bytes()?, receipt))
+}
+
+/// Replace the file with the given index on the server with a local file.
+/// The merkle root is read from stdin and the server must prove that only this file changed,
+/// then the new merkle root is written to stdout.
+pub fn update_file(server_url: &str, file_index: usize, file_path: &str) {
+    let old_root = get_merkle_root().unwrap_or_else(|e| {
+        eprintln!("Failed to read merkle root: {}", e);
+        process::exit(1);
+    });
+    let bytes = std::fs::read(file_path).unwrap_or_else(|e| {
+        eprintln!("Failed to read file {}: {}", file_path, e);
+        process::exit(1);
+    });
+    // a file with an entry keeps its path, and gets the metadata of the new file if it had any
+    let old_entry = download_file_entry(server_url, file_index).unwrap_or_else(|e| {
+        eprintln!(
+            "Failed to download entry of file index {}: {}",
+            file_index, e
+        );
+        process::exit(1);
+    });
+    let hash = hash_file_bytes(&old_root.algorithm, old_root.mode, &bytes);
+    let new_entry = old_entry.as_ref().map(|old_entry| {
+        let with_metadata = old_entry.permissions.is_some() || old_entry.mtime.is_some();
+        FileEntry::of_file(&old_entry.path, Path::new(file_path), with_metadata, hash)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to read file {}: {}", file_path, e);
+                process::exit(1);
+            })
+    });
+    let new_hash = match &new_entry {
+        Some(entry) => entry.leaf(&old_root.algorithm),
+        None => hash,
+    };
+    let mut query = Vec::new();
+    if let Some(entry) = &new_entry {
+        query.push(("path", entry.path.clone()));
+        query.extend(entry.permissions.map(|p| ("permissions", p.to_string())));
+        query.extend(entry.mtime.map(|mtime| ("mtime", mtime.to_string())));
+    }
+    let url = format!("{}/files/{}", server_url, file_index);
+    let response = reqwest::blocking::Client::new()
+        .put(url)
+        .query(&query)
+        .body(bytes)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.text())
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to update file index {}: {}", file_index, e);
+            process::exit(1);
+        });
+    if let Err(e) = verify_updated(
+        &old_root,
+        file_index,
+        old_entry.as_ref(),
+        &new_hash,
+        &response,
+    ) {
+        eprintln!("Update verification failed: {}", e);
+        process::exit(1);
+    }
+}
+
+// Check the server's response to an update: the new root, the old file hash and the proof, one per line.
+// The old entry the new one was made from must be the one of the old file.
+fn verify_updated(
+    old_root: &MerkleRoot,
+    file_index: usize,
+    old_entry: Option<&FileEntry>,
+    new_hash: &[u8; 32],
+    response: &str,
+) -> Result<(), String> {
+    let mut lines = response.lines();
+    let new_root: MerkleRoot = lines.next().ok_or("Missing new merkle root")?.parse()?;
+    let mut hashes = Vec::new();
+    for line in lines {
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(line, &mut hash)
+            .map_err(|e| format!("Invalid hash {}: {}", line, e))?;
+        hashes.push(hash);
+    }
+    let Some((old_hash, proof)) = hashes.split_first() else {
+        return Err("Missing old file hash".to_string());
+    };
+    if old_entry.is_some_and(|entry| entry.leaf(&old_root.algorithm) != *old_hash) {
+        return Err(format!(
+            "Entry of file index {} is not the old one",
+            file_index
+        ));
+    }
+    verify_update(old_root, &new_root, file_index, (old_hash, new_hash), proof)?;
+    eprintln!("Merkle Root after the update: {}", new_root);
+    io::stdout()
+        .write_all(new_root.to_string().as_bytes())
+        .map_err(|e| e.to_string())
+}
+
+// End the upload and get the server's signed receipt of the root of the uploaded files,
+// signed with the pinned key if there is one.
+fn finalize_upload(
+    client: &reqwest::blocking::Client,
+    server_url: &str,
+    server_key: Option<&[u8; 32]>,
+) -> Result<Receipt, String> {
+    let bytes = client
+        .post(format!("{}/upload/finalize", server_url))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .map_err(|e| format!("Failed to finalize upload: {}", e))?;
+    let receipt: Receipt =
+        serde_json::from_slice(&bytes).map_err(|e| format!("Invalid upload receipt: {}", e))?;
+    receipt.verify(server_key)?;
+    let dataset = url_dataset(server_url);
+    if receipt.dataset != dataset {
+        return Err(format!(
+            "Receipt is for dataset {}, not for {}",
+            receipt.dataset, dataset
+        ));
+    }
+    eprintln!(
+        "Merkle root signed by server key {}",
+        hex::encode(receipt.key)
+    );
+    Ok(receipt)
+}
+
+fn download_consistency_proof(
+    server_url: &str,
+    old_size: usize,
 ) -> Result<actix_web::web::Bytes, reqwest::Error> {
-    let url = format!("{}/proofs/{}", server_url, file_index);
+    let url = format!("{}/consistency/{}", server_url, old_size);
     reqwest::blocking::get(url)?.error_for_status()?.bytes()
 }
 
 // read Merkle root from stdin
-fn get_merkle_root() -> Result<[u8; 32], std::io::Error> {
-    let mut merkle_root = [0u8; 32];
+fn get_merkle_root() -> Result<MerkleRoot, std::io::Error> {
     // read a string from stdin
-    let mut merkle_root_hex = String::new();
-    io::stdin().read_line(&mut merkle_root_hex)?;
-    hex::decode_to_slice(merkle_root_hex, &mut merkle_root)
-        .expect("Invalid hex string for merkle root");
-    Ok(merkle_root)
+    let mut merkle_root = String::new();
+    io::stdin().read_line(&mut merkle_root)?;
+    merkle_root
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
 
 /// Download the file with the given index from the server
-/// and verify it with its merkle proof
-pub fn download_verify_file(server_url: &str, file_index: usize) {
-    let bytes = download_file(server_url, file_index).unwrap_or_else(|e| {
-        eprintln!("Failed to download file index {}: {}", file_index, e);
+/// and verify it with its merkle proof, asking for the proof in the given encoding.
+/// With a pinned server key the root must be signed with it as well.
+pub fn download_verify_file(
+    server_url: &str,
+    file_index: usize,
+    encoding: ProofEncoding,
+    server_key: Option<&[u8; 32]>,
+) {
+    let merkle_root = get_merkle_root().unwrap_or_else(|e| {
+        eprintln!("Failed to read merkle root: {}", e);
         process::exit(1);
     });
-    let file_hash = Sha256::digest(&bytes);
-    let proof_bytes = download_proof(server_url, file_index).unwrap_or_else(|e| {
+    // the merkle root records how many files were uploaded, there is nothing to verify past them
+    if file_index >= merkle_root.leaf_count {
+        eprintln!(
+            "File index {} was not uploaded, the merkle root has {} files",
+            file_index, merkle_root.leaf_count
+        );
+        process::exit(1);
+    }
+    let entry = download_file_entry(server_url, file_index).unwrap_or_else(|e| {
         eprintln!(
-            "Failed to download proof for file index {}: {}",
+            "Failed to download entry of file index {}: {}",
             file_index, e
         );
         process::exit(1);
     });
-    let proof = deserialize_proof(&proof_bytes).unwrap();
-    let merkle_root = get_merkle_root().unwrap();
-    match verify_file(&merkle_root, file_index, file_hash.as_ref(), &proof) {
-        Ok(_) => {
-            io::stdout().write_all(&bytes).unwrap();
+    let bytes = download_verified_file(
+        server_url,
+        &merkle_root,
+        file_index,
+        entry.as_ref(),
+        encoding,
+        server_key,
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    if let Some(entry) = entry {
+        eprintln!("File index {} is {}", file_index, entry.path);
+    }
+    io::stdout().write_all(&bytes).unwrap();
+}
+
+/// Download the file with the given path in the manifest from the server of the manifest
+/// and verify it against the root of the manifest, asking for the proof in the given encoding.
+/// The manifest and the root must be signed with the pinned server key.
+pub fn download_verify_path(
+    manifest_path: &str,
+    path: &str,
+    encoding: ProofEncoding,
+    server_key: &[u8; 32],
+) {
+    let manifest = Manifest::load(manifest_path, server_key).unwrap_or_else(|e| {
+        eprintln!("Failed to read manifest {}: {}", manifest_path, e);
+        process::exit(1);
+    });
+    let Some((file_index, entry)) = manifest.find(path) else {
+        eprintln!("There is no file {} in the manifest", path);
+        process::exit(1);
+    };
+    // the entry of the manifest is the one of the root, the server's isn't needed
+    let bytes = download_verified_file(
+        &manifest.server,
+        &manifest.root,
+        file_index,
+        Some(entry),
+        encoding,
+        Some(server_key),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    io::stdout().write_all(&bytes).unwrap();
+}
+
+// Download the file with the given index and verify it with its merkle proof,
+// its leaf is the hash of the given entry if there is one.
+fn download_verified_file(
+    server_url: &str,
+    merkle_root: &MerkleRoot,
+    file_index: usize,
+    entry: Option<&FileEntry>,
+    encoding: ProofEncoding,
+    server_key: Option<&[u8; 32]>,
+) -> Result<actix_web::web::Bytes, String> {
+    let bytes = download_file(server_url, file_index)
+        .map_err(|e| format!("Failed to download file index {}: {}", file_index, e))?;
+    let file_hash = hash_file_bytes(&merkle_root.algorithm, merkle_root.mode, &bytes);
+    let file_hash = file_leaf(&merkle_root.algorithm, merkle_root.mode, entry, file_hash)
+        .map_err(|e| format!("File verification failed: {}", e))?;
+    let (encoding, proof_bytes, receipt) =
+        download_proof(server_url, file_index, merkle_root.leaf_count, encoding).map_err(|e| {
+            format!(
+                "Failed to download proof for file index {}: {}",
+                file_index, e
+            )
+        })?;
+    let proof = decode_proof(&proof_bytes, encoding, merkle_root, file_index)
+        .map_err(|e| format!("Invalid proof for file index {}: {}", file_index, e))?;
+    verify_file(merkle_root, file_index, &file_hash, &proof).map_err(|e| {
+        format!(
+            "File verification failed: {}\nExpected merkle root: {}",
+            e,
+            hex_hash(&merkle_root.hash)
+        )
+    })?;
+    if let Some(server_key) = server_key {
+        check_signed_root(receipt.as_ref(), merkle_root, server_key)?;
+    }
+    Ok(bytes)
+}
+
+/// Download every file of the manifest from the given server, `jobs` files at once,
+/// verify each against the root of the manifest and write it under its path in out_dir,
+/// with its permissions and modification time if the manifest has them.
+/// Files that fail are listed at the end and the program exits with an error code.
+/// The manifest and the root must be signed with the pinned server key.
+pub fn restore_dataset(
+    server_url: &str,
+    manifest_path: &str,
+    out_dir: &str,
+    jobs: usize,
+    encoding: ProofEncoding,
+    server_key: &[u8; 32],
+) {
+    let manifest = Manifest::load(manifest_path, server_key).unwrap_or_else(|e| {
+        eprintln!("Failed to read manifest {}: {}", manifest_path, e);
+        process::exit(1);
+    });
+    let paths: Vec<(&str, bool)> = manifest
+        .files
+        .iter()
+        .map(|file| (file.path.as_str(), file.symlink))
+        .collect();
+    if let Err(e) = check_not_through_links(&paths) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    eprintln!(
+        "Restoring {} files from {} to {}...",
+        manifest.files.len(),
+        server_url,
+        out_dir
+    );
+    let next = AtomicUsize::new(0);
+    let failures = Mutex::new(Vec::new());
+    let links = Mutex::new(Vec::new());
+    let bar = ProgressBar::new(manifest.files.len() as u64);
+    let restore = |file_index: usize, entry: &FileEntry| -> Result<(), String> {
+        let bytes = download_verified_file(
+            server_url,
+            &manifest.root,
+            file_index,
+            Some(entry),
+            encoding,
+            Some(server_key),
+        )?;
+        if entry.symlink {
+            links.lock().unwrap().push((file_index, bytes));
+            return Ok(());
+        }
+        let path = Path::new(out_dir).join(&entry.path);
+        path.parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| entry.restore(&path, &bytes))
+            .map_err(|e| format!("Failed to write file {}: {}", path.display(), e))
+    };
+    thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let file_index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(entry) = manifest.files.get(file_index) else {
+                    break;
+                };
+                if let Err(e) = restore(file_index, entry) {
+                    failures.lock().unwrap().push((file_index, entry, e));
+                }
+                bar.inc(1);
+            });
+        }
+    });
+    bar.finish_and_clear();
+    // links last, so that the files are written before the links that may point to them
+    let mut failures = failures.into_inner().unwrap();
+    for (file_index, bytes) in links.into_inner().unwrap() {
+        let entry = &manifest.files[file_index];
+        let path = Path::new(out_dir).join(&entry.path);
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| entry.restore(&path, &bytes));
+        if let Err(e) = written {
+            let e = format!("Failed to write link {}: {}", path.display(), e);
+            failures.push((file_index, entry, e));
+        }
+    }
+    if failures.is_empty() {
+        eprintln!("{} files restored and verified", manifest.files.len());
+        return;
+    }
+    failures.sort_by_key(|(file_index, _, _)| *file_index);
+    eprintln!(
+        "{} of {} files could not be restored:",
+        failures.len(),
+        manifest.files.len()
+    );
+    for (file_index, entry, e) in failures {
+        eprintln!("  {} {}: {}", file_index, entry.path, e);
+    }
+    process::exit(1);
+}
+
+/// Download the bytes of the file with the given index from the given offset on, at most `length` of them,
+/// and verify them without downloading the whole file: the chunks they are in are verified
+/// with their proofs to the leaf of the file, which is verified with its merkle proof.
+/// With a pinned server key the root must be signed with it as well.
+pub fn download_verify_range(
+    server_url: &str,
+    file_index: usize,
+    offset: u64,
+    length: u64,
+    encoding: ProofEncoding,
+    server_key: Option<&[u8; 32]>,
+) {
+    let merkle_root = get_merkle_root().unwrap_or_else(|e| {
+        eprintln!("Failed to read merkle root: {}", e);
+        process::exit(1);
+    });
+    if file_index >= merkle_root.leaf_count {
+        eprintln!(
+            "File index {} was not uploaded, the merkle root has {} files",
+            file_index, merkle_root.leaf_count
+        );
+        process::exit(1);
+    }
+    let Some(end) = offset.checked_add(length) else {
+        eprintln!(
+            "The range of {} bytes from offset {} is too large",
+            length, offset
+        );
+        process::exit(1);
+    };
+    let entry = download_file_entry(server_url, file_index).unwrap_or_else(|e| {
+        eprintln!(
+            "Failed to download entry of file index {}: {}",
+            file_index, e
+        );
+        process::exit(1);
+    });
+    // with an entry the size of the file is known before any chunk is downloaded,
+    // without one an offset past the end is only found with the last chunk
+    if let Some(entry) = &entry {
+        if offset > entry.size {
+            eprintln!(
+                "Offset {} is past the end of file index {}, which has {} bytes",
+                offset, file_index, entry.size
+            );
+            process::exit(1);
+        }
+    }
+    let end = entry.as_ref().map_or(end, |entry| end.min(entry.size));
+    // an empty range, e.g. at the end of the file, is verified with the chunk before it
+    let last_byte = end.saturating_sub(1);
+    let first_chunk = (offset.min(last_byte) / CHUNK_SIZE as u64) as usize;
+    let last_chunk = (last_byte / CHUNK_SIZE as u64) as usize;
+    let mut bytes = Vec::new();
+    let mut file_hash = None;
+    let mut chunk_count = None;
+    for chunk in first_chunk..=last_chunk {
+        // a range past the end of the file is cut at the last chunk
+        if chunk_count.is_some_and(|count| chunk >= count) {
+            break;
+        }
+        let chunk_bytes = download_chunk(server_url, file_index, chunk).unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to download chunk {} of file index {}: {}",
+                chunk, file_index, e
+            );
+            process::exit(1);
+        });
+        let proof = download_chunk_proof(server_url, file_index, chunk).unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to download proof for chunk {} of file index {}: {}",
+                chunk, file_index, e
+            );
+            process::exit(1);
+        });
+        if proof.algorithm != merkle_root.algorithm
+            || proof.mode != merkle_root.mode
+            || proof.leaf_index != chunk
+            || chunk_count.is_some_and(|count| count != proof.leaf_count)
+        {
+            eprintln!(
+                "Proof for chunk {} of file index {} is for another tree",
+                chunk, file_index
+            );
+            process::exit(1);
+        }
+        chunk_count = Some(proof.leaf_count);
+        let hash = calculate_file_hash_from_chunk(
+            &merkle_root.algorithm,
+            merkle_root.mode,
+            chunk,
+            proof.leaf_count,
+            &chunk_bytes,
+            &proof.siblings,
+        )
+        .unwrap_or_else(|e| {
+            eprintln!("Chunk verification failed: {}", e);
+            process::exit(1);
+        });
+        // every chunk must lead to the same file, which is then verified once
+        if file_hash.is_some_and(|file_hash| file_hash != hash) {
+            eprintln!("Chunk {} is from another file", chunk);
+            process::exit(1);
+        }
+        file_hash = Some(hash);
+        bytes.extend_from_slice(&chunk_bytes);
+    }
+    let (encoding, proof_bytes, receipt) =
+        download_proof(server_url, file_index, merkle_root.leaf_count, encoding).unwrap_or_else(
+            |e| {
+                eprintln!(
+                    "Failed to download proof for file index {}: {}",
+                    file_index, e
+                );
+                process::exit(1);
+            },
+        );
+    let proof =
+        decode_proof(&proof_bytes, encoding, &merkle_root, file_index).unwrap_or_else(|e| {
+            eprintln!("Invalid proof for file index {}: {}", file_index, e);
+            process::exit(1);
+        });
+    let file_hash = file_leaf(
+        &merkle_root.algorithm,
+        merkle_root.mode,
+        entry.as_ref(),
+        file_hash.unwrap(),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("File verification failed: {}", e);
+        process::exit(1);
+    });
+    if let Err(e) = verify_file(&merkle_root, file_index, &file_hash, &proof) {
+        eprintln!("File verification failed: {}", e);
+        eprintln!("Expected merkle root: {}", hex_hash(&merkle_root.hash));
+        process::exit(1);
+    }
+    if let Some(server_key) = server_key {
+        if let Err(e) = check_signed_root(receipt.as_ref(), &merkle_root, server_key) {
+            eprintln!("File verification failed: {}", e);
+            process::exit(1);
         }
-        Err(calculated_merkle_root) => {
-            eprintln!("File verification failed");
+    }
+    let chunks_offset = (first_chunk * CHUNK_SIZE) as u64;
+    let start = (offset - chunks_offset) as usize;
+    if start > bytes.len() {
+        eprintln!(
+            "Offset {} is past the end of file index {}, which has {} bytes",
+            offset,
+            file_index,
+            chunks_offset + bytes.len() as u64
+        );
+        process::exit(1);
+    }
+    let end = (end - chunks_offset).min(bytes.len() as u64) as usize;
+    io::stdout().write_all(&bytes[start..end]).unwrap();
+}
+
+/// Download the files with the given indices from the server,
+/// verify them all with a single merkle multiproof
+/// and write them to the output directory under the paths of their entries,
+/// with their metadata, or named by their index if they have no entry.
+/// Nothing is written unless every file is verified,
+/// and with a pinned server key unless the root is signed with it.
+pub fn download_verify_files(
+    server_url: &str,
+    file_indices: &[usize],
+    out_dir: &str,
+    server_key: Option<&[u8; 32]>,
+) {
+    let merkle_root = get_merkle_root().unwrap_or_else(|e| {
+        eprintln!("Failed to read merkle root: {}", e);
+        process::exit(1);
+    });
+    let mut files = Vec::with_capacity(file_indices.len());
+    let mut leaves = Vec::with_capacity(file_indices.len());
+    let bar = ProgressBar::new(file_indices.len() as u64);
+    for &file_index in file_indices {
+        let bytes = download_file(server_url, file_index).unwrap_or_else(|e| {
+            eprintln!("Failed to download file index {}: {}", file_index, e);
+            process::exit(1);
+        });
+        let entry = download_file_entry(server_url, file_index).unwrap_or_else(|e| {
             eprintln!(
-                "Calculated merkle root: {}",
-                hex_hash(&calculated_merkle_root)
+                "Failed to download entry of file index {}: {}",
+                file_index, e
             );
-            eprintln!("Expected merkle root: {}", hex_hash(&merkle_root));
+            process::exit(1);
+        });
+        let file_hash = hash_file_bytes(&merkle_root.algorithm, merkle_root.mode, &bytes);
+        let file_hash = file_leaf(
+            &merkle_root.algorithm,
+            merkle_root.mode,
+            entry.as_ref(),
+            file_hash,
+        )
+        .and_then(|hash| match &entry {
+            Some(entry) => check_path(&entry.path).map(|_| hash),
+            None => Ok(hash),
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("File index {} verification failed: {}", file_index, e);
+            process::exit(1);
+        });
+        leaves.push((file_index, file_hash));
+        files.push((file_index, entry, bytes));
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+    let (proof_bytes, receipt) =
+        download_multiproof(server_url, file_indices).unwrap_or_else(|e| {
+            eprintln!("Failed to download multiproof: {}", e);
+            process::exit(1);
+        });
+    let proof = deserialize_proof(&proof_bytes).unwrap_or_else(|e| {
+        eprintln!("Invalid multiproof: {}", e);
+        process::exit(1);
+    });
+    if let Err(e) = verify_files(&merkle_root, &leaves, &proof) {
+        eprintln!("Files verification failed: {}", e);
+        process::exit(1);
+    }
+    if let Some(server_key) = server_key {
+        if let Err(e) = check_signed_root(receipt.as_ref(), &merkle_root, server_key) {
+            eprintln!("Files verification failed: {}", e);
+            process::exit(1);
+        }
+    }
+    let mut paths = HashSet::new();
+    let mut links = HashSet::new();
+    for (file_index, entry, _) in &files {
+        let path = match entry {
+            Some(entry) => entry.path.clone(),
+            None => file_index.to_string(),
+        };
+        if entry.as_ref().is_some_and(|entry| entry.symlink) {
+            links.insert(path.clone());
+        }
+        if !paths.insert(path.clone()) {
+            eprintln!("Several files would be written to {}", path);
             process::exit(1);
         }
     }
+    let paths: Vec<(&str, bool)> = paths
+        .iter()
+        .map(|path| (path.as_str(), links.contains(path)))
+        .collect();
+    if let Err(e) = check_not_through_links(&paths) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    // links last, so that the files are written before the links that may point to them
+    files.sort_by_key(|(_, entry, _)| entry.as_ref().is_some_and(|entry| entry.symlink));
+    for (file_index, entry, bytes) in files {
+        let path = match &entry {
+            Some(entry) => Path::new(out_dir).join(&entry.path),
+            None => Path::new(out_dir).join(file_index.to_string()),
+        };
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| match &entry {
+                Some(entry) => entry.restore(&path, &bytes),
+                None => std::fs::write(&path, &bytes),
+            });
+        if let Err(e) = written {
+            eprintln!("Failed to write file {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    }
+    eprintln!(
+        "{} files verified with a {} hash proof",
+        file_indices.len(),
+        proof.len()
+    );
 }
 
-/// Deserialize a merkle proof from a byte array.
-fn deserialize_proof(proof_bytes: &[u8]) -> Result<Vec<[u8; 32]>, String> {
-    if proof_bytes.len() % 32 != 0 {
-        return Err(format!(
-            "Proof size is not a multiple of 32: {}",
-            proof_bytes.len()
-        ));
+// Check that no file would be written through a restored link, wherever it points to,
+// given the paths of the files and whether each is a link.
+fn check_not_through_links(paths: &[(&str, bool)]) -> Result<(), String> {
+    let links: HashSet<&str> = paths
+        .iter()
+        .filter(|(_, link)| *link)
+        .map(|(path, _)| *path)
+        .collect();
+    for (path, _) in paths {
+        let mut dirs = path.match_indices('/').map(|(end, _)| &path[..end]);
+        if let Some(link) = dirs.find(|dir| links.contains(dir)) {
+            return Err(format!(
+                "File {} would be written through the link {}",
+                path, link
+            ));
+        }
     }
-    let mut proof = Vec::<[u8; 32]>::new();
-    let mut i = 0;
-    while i < proof_bytes.len() {
-        let mut hash = [0u8; 32];
-        hash.copy_from_slice(&proof_bytes[i..i + 32]);
-        proof.push(hash);
-        i += 32;
+    Ok(())
+}
+
+/// Download the file with the given name from the server
+/// and verify it with its sparse merkle proof against the sparse merkle root read from stdin.
+/// If the server has no such file, its proof must show that the file wasn't uploaded.
+pub fn download_verify_named_file(server_url: &str, name: &str) {
+    let mut sparse_root = String::new();
+    let sparse_root: SparseRoot = io::stdin()
+        .read_line(&mut sparse_root)
+        .map_err(|e| e.to_string())
+        .and_then(|_| sparse_root.parse())
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read sparse merkle root: {}", e);
+            process::exit(1);
+        });
+    let client = reqwest::blocking::Client::new();
+    let response = client
+        .get(format!("{}/names/file", server_url))
+        .query(&[("name", name)])
+        .send()
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to download file {}: {}", name, e);
+            process::exit(1);
+        });
+    let bytes = if response.status() == reqwest::StatusCode::NOT_FOUND {
+        None
+    } else {
+        Some(
+            response
+                .error_for_status()
+                .and_then(|r| r.bytes())
+                .unwrap_or_else(|e| {
+                    eprintln!("Failed to download file {}: {}", name, e);
+                    process::exit(1);
+                }),
+        )
+    };
+    let proof = client
+        .get(format!("{}/names/proof", server_url))
+        .query(&[("name", name)])
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.bytes())
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| SparseProof::from_bytes(&bytes))
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to download proof for file {}: {}", name, e);
+            process::exit(1);
+        });
+    let entry = download_entry(
+        client
+            .get(format!("{}/names/entry", server_url))
+            .query(&[("name", name)]),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to download entry of file {}: {}", name, e);
+        process::exit(1);
+    });
+    let key = name_key(&sparse_root.algorithm, name);
+    let file_hash = bytes
+        .as_ref()
+        .map(|bytes| hash_file_bytes(&sparse_root.algorithm, sparse_root.mode, bytes))
+        .map(|hash| {
+            file_leaf(
+                &sparse_root.algorithm,
+                sparse_root.mode,
+                entry.as_ref(),
+                hash,
+            )
+        })
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("File verification failed: {}", e);
+            process::exit(1);
+        });
+    if let Err(e) = verify_sparse(&sparse_root, &key, file_hash.as_ref(), &proof) {
+        eprintln!("File verification failed: {}", e);
+        process::exit(1);
+    }
+    match bytes {
+        Some(bytes) => io::stdout().write_all(&bytes).unwrap(),
+        None => {
+            eprintln!("File {} was not uploaded, verified", name);
+            process::exit(1);
+        }
+    }
+}
+
+/// Check that none of the files with the given names are in the dataset of the sorted merkle root read from stdin,
+/// e.g. that the server didn't insert files that were never uploaded.
+/// Every name is checked, the program exits with an error code if any of them is there or can't be verified.
+pub fn audit_absent_files(server_url: &str, names: &[String]) {
+    let mut sorted_root = String::new();
+    let sorted_root: SortedRoot = io::stdin()
+        .read_line(&mut sorted_root)
+        .map_err(|e| e.to_string())
+        .and_then(|_| sorted_root.parse())
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to read sorted merkle root: {}", e);
+            process::exit(1);
+        });
+    let client = reqwest::blocking::Client::new();
+    let mut failed = 0;
+    for name in names {
+        match check_absent(&client, server_url, &sorted_root, name) {
+            Ok(()) => eprintln!("File {} is not in the dataset, verified", name),
+            Err(e) => {
+                eprintln!("File {}: {}", name, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!("{} of {} files failed the audit", failed, names.len());
+        process::exit(1);
+    }
+}
+
+fn check_absent(
+    client: &reqwest::blocking::Client,
+    server_url: &str,
+    sorted_root: &SortedRoot,
+    name: &str,
+) -> Result<(), String> {
+    let response = client
+        .get(format!("{}/names/absence", server_url))
+        .query(&[("name", name)])
+        .send()
+        .map_err(|e| e.to_string())?;
+    if response.status() == reqwest::StatusCode::CONFLICT {
+        return Err("the server has it".to_string());
+    }
+    let proof: AbsenceProof = response
+        .error_for_status()
+        .and_then(|r| r.text())
+        .map_err(|e| format!("Failed to download absence proof: {}", e))?
+        .parse()?;
+    let key = name_key(&sorted_root.0.algorithm, name);
+    verify_absence(sorted_root, &key, &proof)
+}
+
+/// The url of the dataset with the given id on the server, all the routes of the server are under it.
+pub fn dataset_url(server_url: &str, dataset: &str) -> String {
+    format!("{}/datasets/{}", server_url.trim_end_matches('/'), dataset)
+}
+
+// The id of the dataset the url is for, see `dataset_url`, the default one if it's the url of the server.
+fn url_dataset(server_url: &str) -> &str {
+    match server_url.trim_end_matches('/').rsplit_once("/datasets/") {
+        Some((_, dataset)) => dataset,
+        None => "default",
+    }
+}
+
+/// List the ids of the datasets on the server.
+pub fn list_datasets(server_url: &str) {
+    let url = format!("{}/datasets", server_url.trim_end_matches('/'));
+    let ids = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to list datasets: {}", e);
+            process::exit(1);
+        });
+    for id in ids.lines() {
+        println!("{}", id);
     }
-    Ok(proof)
+}
+
+/// Create an empty dataset with the given id on the server.
+pub fn create_dataset(server_url: &str, dataset: &str) {
+    let response = reqwest::blocking::Client::new()
+        .post(dataset_url(server_url, dataset))
+        .send()
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to create dataset {}: {}", dataset, e);
+            process::exit(1);
+        });
+    let status = response.status();
+    if !status.is_success() {
+        eprintln!(
+            "Failed to create dataset {}: {} {}",
+            dataset,
+            status,
+            response.text().unwrap_or_default()
+        );
+        process::exit(1);
+    }
+    eprintln!("Dataset {} created", dataset);
+}
+
+/// Deserialize a merkle proof from a byte array.
+fn deserialize_proof(proof_bytes: &[u8]) -> Result<Vec<[u8; 32]>, String> {
+    decode_raw(proof_bytes).map_err(|e| e.to_string())
 }
@@ -0,0 +1,287 @@
+use crate::hasher::*;
+use crate::merkle::hex_hash;
+use fastcdc::v2020::StreamCDC;
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+// The sizes of content-defined chunks. A change to a file changes the chunk it's in
+// and maybe the next one, the boundaries of all the other chunks stay where they were.
+const MIN_CHUNK_SIZE: u32 = 16 * 1024;
+const AVG_CHUNK_SIZE: u32 = 64 * 1024;
+pub const MAX_CHUNK_SIZE: u32 = 256 * 1024;
+
+/// The key of a chunk in the chunk store: its SHA256, whatever the hash algorithm of the tree.
+pub fn chunk_key(chunk: &[u8]) -> [u8; 32] {
+    Sha256Hasher.hash_parts(&[chunk])
+}
+
+/// Call f with every content-defined chunk read from the reader, in order.
+pub fn for_each_cdc_chunk(
+    reader: impl Read,
+    mut f: impl FnMut(&[u8]) -> Result<(), String>,
+) -> Result<(), String> {
+    for chunk in StreamCDC::new(reader, MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE) {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        f(&chunk.data)?;
+    }
+    Ok(())
+}
+
+/// A file stored as the list of its chunks, the key and the size of each.
+/// Formatted one chunk per line as `<hex key> <size>`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Recipe {
+    pub chunks: Vec<([u8; 32], u64)>,
+}
+
+impl Recipe {
+    /// The recipe of the content read from the reader.
+    pub fn of(reader: impl Read) -> Result<Self, String> {
+        let mut recipe = Recipe::default();
+        for_each_cdc_chunk(reader, |chunk| {
+            recipe.chunks.push((chunk_key(chunk), chunk.len() as u64));
+            Ok(())
+        })?;
+        Ok(recipe)
+    }
+
+    /// The size of the file.
+    pub fn size(&self) -> u64 {
+        self.chunks.iter().map(|(_, size)| size).sum()
+    }
+}
+
+impl fmt::Display for Recipe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (key, size) in &self.chunks {
+            writeln!(f, "{} {}", hex_hash(key), size)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Recipe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut recipe = Recipe::default();
+        for line in s.lines() {
+            let Some((hex_key, size)) = line.split_once(' ') else {
+                return Err(format!("Invalid chunk: {}", line));
+            };
+            let mut key = [0u8; 32];
+            hex::decode_to_slice(hex_key, &mut key)
+                .map_err(|e| format!("Invalid chunk key {}: {}", hex_key, e))?;
+            let size = size
+                .parse()
+                .map_err(|e| format!("Invalid chunk size {}: {}", size, e))?;
+            recipe.chunks.push((key, size));
+        }
+        Ok(recipe)
+    }
+}
+
+/// Chunks stored once by their key, whichever files they are in.
+#[derive(Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        ChunkStore {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn path(&self, key: &[u8; 32]) -> PathBuf {
+        self.dir.join(hex_hash(key))
+    }
+
+    pub fn contains(&self, key: &[u8; 32]) -> bool {
+        self.path(key).exists()
+    }
+
+    pub fn get(&self, key: &[u8; 32]) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key))
+    }
+
+    /// Store a chunk under the given key, which must be its key.
+    /// The chunk is written next to its place and renamed, so a stored chunk is always complete.
+    /// The temporary file has a random name, the store is shared by all the datasets and the same chunk
+    /// may be put by several uploads at once.
+    pub fn put(&self, key: &[u8; 32], chunk: &[u8]) -> io::Result<()> {
+        if chunk_key(chunk) != *key {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Chunk doesn't match its key {}", hex_hash(key)),
+            ));
+        }
+        if self.contains(key) {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let tmp_path = path.with_extension(format!("{:016x}.tmp", rand::random::<u64>()));
+        let written = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .and_then(|mut file| file.write_all(chunk));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        fs::rename(tmp_path, path)
+    }
+
+    /// Check that all the chunks of the recipe are stored with their sizes.
+    pub fn check(&self, recipe: &Recipe) -> Result<(), String> {
+        for (key, size) in &recipe.chunks {
+            match fs::metadata(self.path(key)) {
+                Ok(metadata) if metadata.len() == *size => {}
+                Ok(metadata) => {
+                    return Err(format!(
+                        "Chunk {} has {} bytes, not {}",
+                        hex_hash(key),
+                        metadata.len(),
+                        size
+                    ))
+                }
+                Err(_) => return Err(format!("Missing chunk {}", hex_hash(key))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the file of the recipe, chunk by chunk.
+    pub fn reader(&self, recipe: &Recipe) -> RecipeReader {
+        RecipeReader {
+            store: self.clone(),
+            chunks: recipe.chunks.clone().into_iter(),
+            current: None,
+        }
+    }
+
+    /// Read at most `length` bytes of the file of the recipe from the given offset on,
+    /// opening only the chunks with the bytes.
+    pub fn read_range(&self, recipe: &Recipe, offset: u64, length: u64) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        let mut chunk_start = 0;
+        for (key, size) in &recipe.chunks {
+            let chunk_end = chunk_start + size;
+            let end = offset.saturating_add(length);
+            if chunk_end > offset && chunk_start < end {
+                let mut chunk = File::open(self.path(key))?;
+                let start = offset.saturating_sub(chunk_start);
+                chunk.seek(SeekFrom::Start(start))?;
+                chunk
+                    .take(end.min(chunk_end) - chunk_start - start)
+                    .read_to_end(&mut bytes)?;
+            }
+            chunk_start = chunk_end;
+        }
+        Ok(bytes)
+    }
+}
+
+/// Reads the file of a recipe from the chunk store, see [`ChunkStore::reader`].
+pub struct RecipeReader {
+    store: ChunkStore,
+    chunks: std::vec::IntoIter<([u8; 32], u64)>,
+    current: Option<File>,
+}
+
+impl Read for RecipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(chunk) = &mut self.current {
+                let read = chunk.read(buf)?;
+                if read > 0 || buf.is_empty() {
+                    return Ok(read);
+                }
+            }
+            match self.chunks.next() {
+                Some((key, _)) => self.current = Some(File::open(self.store.path(&key))?),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dedup::*;
+
+    // Bytes that look random enough for the chunk boundaries to depend on the content.
+    fn content(seed: u64, size: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn temp_store(name: &str) -> ChunkStore {
+        ChunkStore::new(std::env::temp_dir().join(format!(
+            "mermade-{}-{}",
+            name,
+            std::process::id()
+        )))
+    }
+
+    #[test]
+    fn similar_files_share_chunks() {
+        let original = content(1, 1 << 20);
+        // a few bytes inserted in the middle shift everything after them
+        let mut edited = original.clone();
+        edited.splice(500_000..500_000, *b"inserted");
+        let original = Recipe::of(&original[..]).unwrap();
+        let edited = Recipe::of(&edited[..]).unwrap();
+        let new_chunks = edited
+            .chunks
+            .iter()
+            .filter(|chunk| !original.chunks.contains(chunk))
+            .count();
+        assert!(new_chunks <= 2, "{} new chunks", new_chunks);
+        assert_eq!(edited.size(), original.size() + 8);
+    }
+
+    #[test]
+    fn stored_recipes_read_back() {
+        let store = temp_store("chunks");
+        let bytes = content(2, 300_000);
+        let recipe = Recipe::of(&bytes[..]).unwrap();
+        assert_eq!(recipe.to_string().parse::<Recipe>(), Ok(recipe.clone()));
+        assert!(store.check(&recipe).is_err());
+        let mut offset = 0;
+        for (key, size) in &recipe.chunks {
+            let chunk = &bytes[offset..offset + *size as usize];
+            // a chunk is only stored under its own key
+            assert!(store.put(&[0; 32], chunk).is_err());
+            store.put(key, chunk).unwrap();
+            offset += *size as usize;
+        }
+        assert_eq!(store.check(&recipe), Ok(()));
+        let mut read = Vec::new();
+        store.reader(&recipe).read_to_end(&mut read).unwrap();
+        let range = store.read_range(&recipe, 70_000, 100_000).unwrap();
+        let past_end = store.read_range(&recipe, 299_990, 100).unwrap();
+        let to_end = store.read_range(&recipe, 200_000, u64::MAX).unwrap();
+        let leftover = fs::read_dir(&store.dir).unwrap().count();
+        fs::remove_dir_all(&store.dir).unwrap();
+        assert_eq!(read, bytes);
+        assert_eq!(range, &bytes[70_000..170_000]);
+        assert_eq!(past_end, &bytes[299_990..]);
+        assert_eq!(to_end, &bytes[200_000..]);
+        // no temporary files are left next to the chunks
+        assert_eq!(leftover, recipe.chunks.len());
+    }
+}
@@ -0,0 +1,294 @@
+use crate::hasher::*;
+use crate::merkle::hex_hash;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+// Entry leaves are hashed with their own prefix, next to the RFC 6962 leaf and node prefixes,
+// so an entry can't be passed off as the content of a file or the other way around.
+const ENTRY_PREFIX: u8 = 0x02;
+
+/// What the leaf of a file commits to when it's uploaded with its path:
+/// the path in the dataset, the size, optionally the unix permissions and the modification time,
+/// and the hash of the content, see [`crate::chunks::hash_file_reader`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileEntry {
+    /// The relative path with `/` separators, see [`check_path`].
+    pub path: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<u32>,
+    /// Seconds since the unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(with = "hex_serde")]
+    pub hash: [u8; 32],
+    /// The file is a symbolic link, its content is the target.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub symlink: bool,
+}
+
+mod hex_serde {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(hash: &[u8; 32], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex_hash(hash))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 32], D::Error> {
+        let hex_string = String::deserialize(deserializer)?;
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(&hex_string, &mut hash).map_err(D::Error::custom)?;
+        Ok(hash)
+    }
+}
+
+/// Check that a path is relative and stays inside the directory it's restored to:
+/// not empty, no leading `/`, no empty, `.` or `..` components, no backslashes or NUL bytes.
+pub fn check_path(path: &str) -> Result<(), String> {
+    if path.is_empty() {
+        return Err("Empty path".to_string());
+    }
+    if path.contains(['\\', '\0']) {
+        return Err(format!("Invalid character in path {:?}", path));
+    }
+    if path
+        .split('/')
+        .any(|component| component.is_empty() || component == "." || component == "..")
+    {
+        return Err(format!("Path {:?} must be relative and normalized", path));
+    }
+    Ok(())
+}
+
+impl FileEntry {
+    /// The entry of a local file stored under the given path in the dataset,
+    /// with its permissions and modification time if `with_metadata` is set.
+    pub fn of_file(
+        path: &str,
+        file: &Path,
+        with_metadata: bool,
+        hash: [u8; 32],
+    ) -> io::Result<Self> {
+        let (permissions, mtime) = if with_metadata {
+            file_metadata(file)?
+        } else {
+            (None, None)
+        };
+        Ok(FileEntry {
+            path: path.to_string(),
+            size: fs::metadata(file)?.len(),
+            permissions,
+            mtime,
+            hash,
+            symlink: false,
+        })
+    }
+
+    /// Check that this is the entry of the file with the given content hash.
+    pub fn check(&self, hash: &[u8; 32]) -> Result<(), String> {
+        if self.hash != *hash {
+            return Err(format!(
+                "File {} doesn't match the hash of its entry",
+                self.path
+            ));
+        }
+        Ok(())
+    }
+
+    // The bytes the leaf is the hash of, every field with its length or presence,
+    // so no two entries have the same encoding. Links have a byte more after the hash,
+    // which keeps the encoding of the other entries as it was before there were links.
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![ENTRY_PREFIX];
+        bytes.extend_from_slice(&(self.path.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(self.path.as_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        match self.permissions {
+            Some(permissions) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&permissions.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        match self.mtime {
+            Some(mtime) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&mtime.to_be_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes.extend_from_slice(&self.hash);
+        if self.symlink {
+            bytes.push(1);
+        }
+        bytes
+    }
+
+    /// The leaf of the file in a tree of the RFC 6962 mode, the only one with room for another prefix.
+    pub fn leaf<H: MerkleHasher>(&self, hasher: &H) -> [u8; 32] {
+        hasher.hash_parts(&[&self.encode()])
+    }
+
+    /// Give a restored file the permissions and the modification time of the entry, those it has.
+    pub fn apply(&self, file: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(permissions) = self.permissions {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(file, fs::Permissions::from_mode(permissions))?;
+        }
+        if let Some(mtime) = self.mtime {
+            let since = Duration::from_secs(mtime.unsigned_abs());
+            let modified = if mtime >= 0 {
+                SystemTime::UNIX_EPOCH + since
+            } else {
+                SystemTime::UNIX_EPOCH - since
+            };
+            fs::File::options()
+                .write(true)
+                .open(file)?
+                .set_modified(modified)?;
+        }
+        Ok(())
+    }
+
+    /// Write a restored file with the given content: a link to the content if the entry is a link,
+    /// otherwise the content with the permissions and the modification time of the entry.
+    pub fn restore(&self, file: &Path, content: &[u8]) -> io::Result<()> {
+        if !self.symlink {
+            fs::write(file, content)?;
+            return self.apply(file);
+        }
+        let target = std::str::from_utf8(content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, file);
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("Can't restore the link to {} on this platform", target),
+        ));
+    }
+}
+
+/// The unix permissions, where there are any, and the modification time of a local file.
+pub fn file_metadata(file: &Path) -> io::Result<(Option<u32>, Option<i64>)> {
+    let metadata = fs::metadata(file)?;
+    #[cfg(unix)]
+    let permissions = {
+        use std::os::unix::fs::PermissionsExt;
+        Some(metadata.permissions().mode() & 0o7777)
+    };
+    #[cfg(not(unix))]
+    let permissions = None;
+    let mtime = match metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    };
+    Ok((permissions, Some(mtime)))
+}
+
+/// Check that entries can be bound into the leaves of a tree of the given mode.
+pub fn check_entry_mode(mode: TreeMode) -> Result<(), String> {
+    match mode {
+        TreeMode::Rfc6962 => Ok(()),
+        TreeMode::Plain => {
+            Err("Paths can only be bound into the leaves of rfc6962 trees".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::entry::*;
+
+    fn entry() -> FileEntry {
+        FileEntry {
+            path: "docs/notes.txt".to_string(),
+            size: 6,
+            permissions: Some(0o644),
+            mtime: Some(1_700_000_000),
+            hash: Sha256Hasher.hash_leaf(TreeMode::Rfc6962, b"file 1"),
+            symlink: false,
+        }
+    }
+
+    #[test]
+    fn every_field_is_bound() {
+        let entry = entry();
+        let leaf = entry.leaf(&Sha256Hasher);
+        let changed = [
+            FileEntry {
+                path: "docs/other.txt".to_string(),
+                ..entry.clone()
+            },
+            FileEntry {
+                size: 7,
+                ..entry.clone()
+            },
+            FileEntry {
+                permissions: None,
+                ..entry.clone()
+            },
+            FileEntry {
+                mtime: Some(0),
+                ..entry.clone()
+            },
+            FileEntry {
+                hash: [0; 32],
+                ..entry.clone()
+            },
+            FileEntry {
+                symlink: true,
+                ..entry.clone()
+            },
+        ];
+        for other in changed {
+            assert_ne!(other.leaf(&Sha256Hasher), leaf, "{:?}", other);
+        }
+        // a file with the encoding of an entry as its content has another leaf
+        assert_ne!(
+            Sha256Hasher.hash_leaf(TreeMode::Rfc6962, &entry.encode()),
+            leaf
+        );
+    }
+
+    #[test]
+    fn entries_roundtrip_as_json() {
+        let entry = entry();
+        let json = serde_json::to_string(&entry).unwrap();
+        assert!(json.contains(&hex_hash(&entry.hash)));
+        assert_eq!(serde_json::from_str::<FileEntry>(&json).unwrap(), entry);
+        let bare = FileEntry {
+            permissions: None,
+            mtime: None,
+            ..entry
+        };
+        let json = serde_json::to_string(&bare).unwrap();
+        assert!(!json.contains("mtime") && !json.contains("symlink"));
+        assert_eq!(serde_json::from_str::<FileEntry>(&json).unwrap(), bare);
+    }
+
+    #[test]
+    fn paths_stay_inside() {
+        for path in ["a", "a/b.txt", "dir/.hidden", "a..b"] {
+            assert_eq!(check_path(path), Ok(()), "{}", path);
+        }
+        for path in [
+            "",
+            "/etc/passwd",
+            "a//b",
+            "a/",
+            "./a",
+            "a/../../b",
+            "..",
+            "a\\b",
+        ] {
+            assert!(check_path(path).is_err(), "{}", path);
+        }
+    }
+}
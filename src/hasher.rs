@@ -0,0 +1,308 @@
+use sha2::Digest;
+use sha2::Sha256;
+use sha2::Sha512_256;
+use std::fmt;
+use std::str::FromStr;
+
+/// How leaves and internal nodes of a Merkle tree are hashed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeMode {
+    /// Leaves are `H(data)` and nodes are `H(left || right)`.
+    /// A leaf can't be told apart from an internal node, kept for old roots.
+    Plain,
+    /// RFC 6962 domain separation: leaves are `H(0x00 || data)`
+    /// and nodes are `H(0x01 || left || right)`.
+    Rfc6962,
+}
+
+impl TreeMode {
+    /// The id of the mode in binary formats.
+    pub fn id(&self) -> u8 {
+        match self {
+            TreeMode::Plain => 0,
+            TreeMode::Rfc6962 => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        [TreeMode::Plain, TreeMode::Rfc6962]
+            .into_iter()
+            .find(|mode| mode.id() == id)
+    }
+}
+
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+impl fmt::Display for TreeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TreeMode::Plain => write!(f, "plain"),
+            TreeMode::Rfc6962 => write!(f, "rfc6962"),
+        }
+    }
+}
+
+impl FromStr for TreeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(TreeMode::Plain),
+            "rfc6962" => Ok(TreeMode::Rfc6962),
+            _ => Err(format!("Unknown tree mode: {}", s)),
+        }
+    }
+}
+
+/// A hash function a Merkle tree can be built with.
+/// All supported functions produce 32 byte hashes.
+/// Hashers are shared between threads when hashing in parallel.
+pub trait MerkleHasher: Sync {
+    /// The id recorded with every root built with this hasher.
+    fn algorithm(&self) -> HashAlgorithm;
+
+    /// Hash the parts one after another, as if they were one slice, without copying them.
+    fn hash_parts(&self, parts: &[&[u8]]) -> [u8; 32];
+
+    /// Hash the content of a leaf according to the tree mode.
+    fn hash_leaf(&self, mode: TreeMode, data: &[u8]) -> [u8; 32] {
+        match mode {
+            TreeMode::Plain => self.hash_parts(&[data]),
+            TreeMode::Rfc6962 => self.hash_parts(&[&[LEAF_PREFIX], data]),
+        }
+    }
+
+    /// Hash two child nodes into their parent according to the tree mode.
+    fn hash_node(&self, mode: TreeMode, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        match mode {
+            TreeMode::Plain => self.hash_parts(&[left, right]),
+            TreeMode::Rfc6962 => self.hash_parts(&[&[NODE_PREFIX], left, right]),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha256Hasher;
+
+impl MerkleHasher for Sha256Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Sha256
+    }
+
+    fn hash_parts(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Sha512_256Hasher;
+
+impl MerkleHasher for Sha512_256Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Sha512_256
+    }
+
+    fn hash_parts(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut hasher = Sha512_256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blake3Hasher;
+
+impl MerkleHasher for Blake3Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Blake3
+    }
+
+    fn hash_parts(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut hasher = blake3::Hasher::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+}
+
+/// `SHA256(SHA256(data))`, the hash Bitcoin uses for transactions and its Merkle trees.
+/// Note that our trees still promote odd nodes where Bitcoin duplicates them.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DoubleSha256Hasher;
+
+impl MerkleHasher for DoubleSha256Hasher {
+    fn algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::DoubleSha256
+    }
+
+    fn hash_parts(&self, parts: &[&[u8]]) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        Sha256::digest(hasher.finalize()).into()
+    }
+}
+
+/// The hash algorithm id, used where the algorithm is only known at runtime,
+/// e.g. when it's read from a root or negotiated with the server.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Sha512_256,
+    Blake3,
+    DoubleSha256,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 4] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha512_256,
+        HashAlgorithm::Blake3,
+        HashAlgorithm::DoubleSha256,
+    ];
+
+    /// The id of the algorithm in binary formats.
+    pub fn id(&self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => 1,
+            HashAlgorithm::Sha512_256 => 2,
+            HashAlgorithm::Blake3 => 3,
+            HashAlgorithm::DoubleSha256 => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.id() == id)
+    }
+
+    fn hasher(&self) -> &'static dyn MerkleHasher {
+        match self {
+            HashAlgorithm::Sha256 => &Sha256Hasher,
+            HashAlgorithm::Sha512_256 => &Sha512_256Hasher,
+            HashAlgorithm::Blake3 => &Blake3Hasher,
+            HashAlgorithm::DoubleSha256 => &DoubleSha256Hasher,
+        }
+    }
+}
+
+impl MerkleHasher for HashAlgorithm {
+    fn algorithm(&self) -> HashAlgorithm {
+        *self
+    }
+
+    fn hash_parts(&self, parts: &[&[u8]]) -> [u8; 32] {
+        self.hasher().hash_parts(parts)
+    }
+
+    fn hash_leaf(&self, mode: TreeMode, data: &[u8]) -> [u8; 32] {
+        self.hasher().hash_leaf(mode, data)
+    }
+
+    fn hash_node(&self, mode: TreeMode, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        self.hasher().hash_node(mode, left, right)
+    }
+}
+
+// A borrowed hasher hashes the same, e.g. to build a root with a hasher that is still needed after.
+impl<H: MerkleHasher + ?Sized> MerkleHasher for &H {
+    fn algorithm(&self) -> HashAlgorithm {
+        (**self).algorithm()
+    }
+
+    fn hash_parts(&self, parts: &[&[u8]]) -> [u8; 32] {
+        (**self).hash_parts(parts)
+    }
+
+    fn hash_leaf(&self, mode: TreeMode, data: &[u8]) -> [u8; 32] {
+        (**self).hash_leaf(mode, data)
+    }
+
+    fn hash_node(&self, mode: TreeMode, left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        (**self).hash_node(mode, left, right)
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => write!(f, "sha256"),
+            HashAlgorithm::Sha512_256 => write!(f, "sha512_256"),
+            HashAlgorithm::Blake3 => write!(f, "blake3"),
+            HashAlgorithm::DoubleSha256 => write!(f, "sha256d"),
+        }
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.to_string() == s)
+            .ok_or_else(|| format!("Unknown hash algorithm: {}", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn known_answers() {
+        let cases = [
+            (
+                HashAlgorithm::Sha256,
+                hex!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+            ),
+            (
+                HashAlgorithm::Sha512_256,
+                hex!("53048e2681941ef99b2e29b76b4c7dabe4c2d0c634fc6d46e0e2f13107e7af23"),
+            ),
+            (
+                HashAlgorithm::Blake3,
+                hex!("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+            ),
+            (
+                HashAlgorithm::DoubleSha256,
+                hex!("4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358"),
+            ),
+        ];
+        for (algorithm, expected) in cases {
+            assert_eq!(algorithm.hash_leaf(TreeMode::Plain, b"abc"), expected);
+            assert_eq!(algorithm.hash_parts(&[b"a", b"", b"bc"]), expected);
+        }
+    }
+
+    #[test]
+    fn algorithm_names_roundtrip() {
+        for algorithm in HashAlgorithm::ALL {
+            assert_eq!(algorithm.to_string().parse(), Ok(algorithm));
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn ids_roundtrip() {
+        for algorithm in HashAlgorithm::ALL {
+            assert_eq!(HashAlgorithm::from_id(algorithm.id()), Some(algorithm));
+        }
+        for mode in [TreeMode::Plain, TreeMode::Rfc6962] {
+            assert_eq!(TreeMode::from_id(mode.id()), Some(mode));
+        }
+        assert_eq!(HashAlgorithm::from_id(0), None);
+    }
+}
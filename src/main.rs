@@ -1,39 +1,347 @@
 use std::env;
+mod chunks;
 mod client;
+mod dedup;
+mod entry;
+mod hasher;
+mod manifest;
 mod merkle;
+mod mmr;
+mod proof;
+mod receipt;
 mod server;
+mod sorted;
+mod sparse;
+mod tree_file;
+mod walk;
 use client::*;
+use hasher::{HashAlgorithm, TreeMode};
+use proof::ProofEncoding;
+use walk::{SymlinkPolicy, WalkOptions};
+
+// Remove `--name <value>` from the arguments and return the value if it was given.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = args.iter().position(|arg| arg == name)?;
+    if pos + 1 >= args.len() {
+        eprintln!("Missing value for {}", name);
+        std::process::exit(1);
+    }
+    let value = args.remove(pos + 1);
+    args.remove(pos);
+    Some(value)
+}
+
+// The server key a manifest is checked against, which must be pinned:
+// the key of the receipt in the manifest would only prove that the manifest agrees with itself.
+fn manifest_key(server_key: Option<[u8; 32]>) -> [u8; 32] {
+    server_key.unwrap_or_else(|| {
+        eprintln!("A manifest is only used with the server key pinned with --server-key");
+        std::process::exit(1);
+    })
+}
+
+// Remove every `--name <value>` from the arguments and return the values, in order.
+fn take_options(args: &mut Vec<String>, name: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    while let Some(value) = take_option(args, name) {
+        values.push(value);
+    }
+    values
+}
+
+// Remove the `--name` flag from the arguments and return whether it was given.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let Some(pos) = args.iter().position(|arg| arg == name) else {
+        return false;
+    };
+    args.remove(pos);
+    true
+}
 
 fn show_usage() {
     println!("Usage: mermade <command> [args]");
     println!("Commands:");
     println!("  server <port> -- will start the server on the given port");
-    println!("  upload <server url> <files_dir> -- will upload all files in the <files_dir> directory to the server,
-          output the merkle root to STDOUT and delete the files.
-          The Merkle Root is written to STDOUT in HEX format.
+    println!("  datasets <server url> -- will list the datasets on the server, one per line, the default one is always there");
+    println!("  create-dataset <server url> <id> -- will create an empty dataset with the given id on the server,
+          made of letters, digits, `-`, `_` and `.`. Every command with a <server url> takes --dataset <id>
+          to use the dataset with the id instead of the default one, so uploads to different datasets never touch each other.
+          Example: mermade create-dataset http://localhost:8080 photos && mermade upload --dataset photos http://localhost:8080 photos
+    ");
+    println!("  upload [--mode <plain|rfc6962>] [--algorithm <sha256|sha512_256|blake3|sha256d>] [--by-name|--sorted] [--dedup] [--bind-paths|--bind-metadata] [--manifest <manifest>]
+          [--dry-run|--keep|--trash <dir>] [--include <glob>]... [--exclude <glob>]... [--symlinks <skip|follow|store>] [--server-key <key>] <server url> <files_dir> -- will upload all files
+          in the <files_dir> directory and its subdirectories to the server, output the merkle root to STDOUT and delete the files.
+          The files are ordered by their paths relative to <files_dir>, with `/` separators and Unicode names in NFC form,
+          so the root is the same on every platform.
+          The Merkle Root is written to STDOUT as <algorithm>:<mode>:<leaf count>:<HEX root>.
+          The upload is finalized on the server, which answers with its own merkle root and number of files,
+          and if they are not the ones the client calculated, nothing is written or deleted and the program exits with an error code.
+          The server signs them with its Ed25519 key, the receipt is kept in the manifest. With --server-key the receipt
+          must be signed with <key>, the server's public key in hex, as printed when it starts and returned by GET /key.
+          The tree mode defaults to rfc6962, which hashes leaves and nodes with different prefixes.
+          The hash algorithm must be supported by the server, by default the server's first choice is used.
+          With --by-name the root of the sparse Merkle tree of the files keyed by their names is written instead,
+          as <algorithm>:<mode>:sparse:<HEX root>, for download-name.
+          With --sorted the root of the tree of the files ordered by the hashes of their names is written instead,
+          as <algorithm>:<mode>:sorted:<leaf count>:<HEX root>, for audit.
+          With --dedup the files are split into content-defined chunks and only the chunks the server doesn't have are sent,
+          e.g. after an upload of a similar dataset. The merkle root is the same either way.
+          With --bind-paths the leaf of every file commits to its name and size as well as its content,
+          --bind-metadata adds its permissions and modification time, so the files can be restored as they were.
+          Both need the rfc6962 mode.
+          With --manifest the manifest of the upload is written to <manifest> as well: the server url, the merkle root,
+          the receipt and the path, size and hash of every file in the index order, for download --manifest and restore.
+          It needs --bind-paths or --bind-metadata, so the paths are in the merkle root.
+          With --include only the files with a path matching one of the globs are uploaded, with --exclude the files
+          and directories matching one of them are left out, `*` stays within a directory and `**` crosses them.
+          Symlinks are skipped by default, followed as the file or directory they point to with --symlinks follow,
+          or uploaded as links with --symlinks store, which needs --bind-paths or --bind-metadata.
+          The files are only deleted once the server has accepted all of them and a random sample of them was downloaded
+          and verified against the merkle root. With --dry-run the sample is verified and the files that would be deleted
+          are listed, with --keep nothing is deleted, with --trash the files are moved to <dir> under their paths instead.
+          Files reached through a followed symlink are never deleted.
           Example: mermade upload http://localhost:8080 files > merkle_root.txt
     ");
-    println!("  download <server url> <index> -- will download the file with the given index from the server,
+    println!("  append [--dedup] [--bind-paths|--bind-metadata] [--dry-run|--keep|--trash <dir>] [--include <glob>]... [--exclude <glob>]... [--symlinks <skip|follow|store>] [--server-key <key>] <server url> <files_dir> -- will upload all files in the <files_dir> directory to the server
+          as new files after the ones of the Merkle Root read from STDIN, check that the server kept all the old files
+          and added exactly the new ones, output the new merkle root to STDOUT and delete the files as upload does.
+          Example: mermade append http://localhost:8080 more_files < merkle_root.txt > new_merkle_root.txt
+    ");
+    println!("  update <server url> <index> <file> -- will replace the file with the given index on the server with <file>,
+          check that the server changed only this file and output the new merkle root to STDOUT.
+          The Merkle Root is read from STDIN as written by upload.
+          Example: mermade update http://localhost:8080 3 fixed.txt < merkle_root.txt > new_merkle_root.txt
+    ");
+    println!("  download [--proof-encoding <binary|json|cbor>] [--server-key <key>] <server url> <index> -- will download the file with the given index from the server,
           verify its merkle proof and output the file to stdout.
-          The Merkle Root is read from STDIN in HEX format.
+          The Merkle Root is read from STDIN as written by upload.
+          The proof is requested in the binary format by default, any of the encodings the server answers with is understood.
           If the merkle proof is invalid, the program will exit with an error code.
+          With --server-key the root must also be signed with <key>: the server attaches its receipt of the root to every proof.
           Example: mermade download http://localhost:8080 0 > file.txt < merkle_root.txt
+  download [--proof-encoding <binary|json|cbor>] --server-key <key> --manifest <manifest> <path> -- will download the file with the given path
+          from the server of the manifest written by upload, verify it against the root of the manifest and output it to stdout.
+          The manifest is only used if its files lead to its root and its receipt is signed with <key>, as is the root of the proof.
+          Example: mermade download --server-key <key> --manifest manifest.json notes.txt > notes.txt
+    ");
+    println!("  download-range [--proof-encoding <binary|json|cbor>] [--server-key <key>] <server url> <index> <offset> <length> -- will download
+          at most <length> bytes of the file with the given index from <offset> on, verify them and output them to stdout.
+          Only the chunks of the file with the bytes are downloaded, each verified with its proof to the hash of the file,
+          which is verified with its merkle proof. Files are split into chunks of 1 MiB.
+          A range past the end of the file is cut at the end, an offset past the end is an error.
+          The Merkle Root is read from STDIN as written by upload.
+          If any proof is invalid, the program will exit with an error code.
+          Example: mermade download-range http://localhost:8080 0 1048576 4096 > part.bin < merkle_root.txt
+    ");
+    println!("  download-name <server url> <name> -- will download the file with the given name from the server,
+          verify its sparse merkle proof and output the file to stdout.
+          The Sparse Merkle Root is read from STDIN as written by upload --by-name.
+          If there is no such file, the server must prove it and the program will exit with an error code.
+          Example: mermade download-name http://localhost:8080 notes.txt > notes.txt < sparse_root.txt
+    ");
+    println!("  audit <server url> <name>... -- will check that the server has none of the files with the given names,
+          e.g. that it didn't insert files that were never uploaded, with proofs made of the files next to each name.
+          The Sorted Merkle Root is read from STDIN as written by upload --sorted.
+          If any of the files is there or its absence can't be verified, the program will exit with an error code.
+          Example: mermade audit http://localhost:8080 extra.txt other.txt < sorted_root.txt
+    ");
+    println!("  download-batch [--server-key <key>] <server url> <out_dir> <index>... -- will download the files with the given indices from the server,
+          verify them all with a single merkle multiproof and write them to <out_dir> named by their index,
+          or under their verified paths, with their permissions and modification times, if they were uploaded with them.
+          Stored symlinks are restored as links, nothing is written through them.
+          The Merkle Root is read from STDIN as written by upload.
+          If the merkle multiproof is invalid, no file is written and the program will exit with an error code.
+          Example: mermade download-batch http://localhost:8080 restored 0 3 5 < merkle_root.txt
+    ");
+    println!("  restore [--jobs <n>] [--proof-encoding <binary|json|cbor>] --server-key <key> <server url> <manifest> <out_dir> -- will download
+          every file of the manifest written by upload from the server, <n> at once, 8 by default, verify each against
+          the root of the manifest and write it to <out_dir> under its path, with the layout the files were uploaded with.
+          The server may be another one than the one in the manifest, e.g. after the files were moved,
+          but the manifest and the roots must be signed with <key>.
+          Files that can't be downloaded or verified are listed at the end and the program will exit with an error code.
+          Example: mermade restore --server-key <key> http://localhost:8080 manifest.json restored
     ");
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let mode = take_option(&mut args, "--mode")
+        .map(|mode| {
+            mode.parse::<TreeMode>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            })
+        })
+        .unwrap_or(TreeMode::Rfc6962);
+    let algorithm = take_option(&mut args, "--algorithm").map(|algorithm| {
+        algorithm.parse::<HashAlgorithm>().unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    let proof_encoding = take_option(&mut args, "--proof-encoding")
+        .map(|encoding| {
+            encoding.parse::<ProofEncoding>().unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            })
+        })
+        .unwrap_or_default();
+    let manifest = take_option(&mut args, "--manifest");
+    let server_key = take_option(&mut args, "--server-key").map(|key| {
+        receipt::parse_key(&key).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    let jobs = take_option(&mut args, "--jobs")
+        .map(|jobs| match jobs.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => jobs,
+            _ => {
+                eprintln!(
+                    "The number of jobs must be a positive integer, not {}",
+                    jobs
+                );
+                std::process::exit(1);
+            }
+        })
+        .unwrap_or(8);
+    let root_kind = match (
+        take_flag(&mut args, "--by-name"),
+        take_flag(&mut args, "--sorted"),
+    ) {
+        (false, false) => RootKind::Indexed,
+        (true, false) => RootKind::Sparse,
+        (false, true) => RootKind::Sorted,
+        (true, true) => {
+            eprintln!("Only one of --by-name and --sorted can be given");
+            std::process::exit(1);
+        }
+    };
+    let dedup = take_flag(&mut args, "--dedup");
+    let binding = match (
+        take_flag(&mut args, "--bind-paths"),
+        take_flag(&mut args, "--bind-metadata"),
+    ) {
+        (false, false) => LeafBinding::Content,
+        (true, false) => LeafBinding::Path,
+        (_, true) => LeafBinding::Metadata,
+    };
+    let deletion = match (
+        take_flag(&mut args, "--dry-run"),
+        take_flag(&mut args, "--keep"),
+        take_option(&mut args, "--trash"),
+    ) {
+        (false, false, None) => Deletion::Delete,
+        (true, false, None) => Deletion::DryRun,
+        (false, true, None) => Deletion::Keep,
+        (false, false, Some(trash_dir)) => Deletion::Trash(trash_dir.into()),
+        _ => {
+            eprintln!("Only one of --dry-run, --keep and --trash can be given");
+            std::process::exit(1);
+        }
+    };
+    let walk = WalkOptions {
+        include: take_options(&mut args, "--include"),
+        exclude: take_options(&mut args, "--exclude"),
+        symlinks: take_option(&mut args, "--symlinks")
+            .map(|symlinks| {
+                symlinks.parse::<SymlinkPolicy>().unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                })
+            })
+            .unwrap_or_default(),
+    };
+    // with --dataset the commands use the dataset under the url of the server
+    let dataset = take_option(&mut args, "--dataset");
+    let url = |server_url: &str| match &dataset {
+        Some(dataset) => dataset_url(server_url, dataset),
+        None => server_url.to_string(),
+    };
     if args.len() == 3 && args[1] == "server" {
         let _ = server::server(&args[2]);
-    } else if args.len() == 4 && args[1] == "upload" {
-        let server_url = &args[2];
+    } else if args.len() == 3 && args[1] == "datasets" {
+        list_datasets(&args[2]);
+    } else if args.len() == 4 && args[1] == "create-dataset" {
+        create_dataset(&args[2], &args[3]);
+    } else if args.len() == 4 && (args[1] == "upload" || args[1] == "append") {
+        let server_url = &url(&args[2]);
         let files_dir = &args[3];
-        upload_all_and_delete(server_url, files_dir);
+        let options = UploadOptions {
+            mode,
+            algorithm,
+            root_kind,
+            dedup,
+            binding,
+            manifest,
+            walk,
+            deletion,
+            server_key,
+        };
+        if args[1] == "upload" {
+            upload_all_and_delete(server_url, files_dir, &options);
+        } else {
+            append_all_and_delete(server_url, files_dir, &options);
+        }
+    } else if args.len() == 5 && args[1] == "update" {
+        let server_url = &url(&args[2]);
+        let file_index = args[3].parse::<usize>().unwrap();
+        let file_path = &args[4];
+        update_file(server_url, file_index, file_path);
+    } else if args.len() == 3 && args[1] == "download" && manifest.is_some() {
+        let path = &args[2];
+        download_verify_path(
+            manifest.as_deref().unwrap(),
+            path,
+            proof_encoding,
+            &manifest_key(server_key),
+        );
     } else if args.len() == 4 && args[1] == "download" {
-        let server_url = &args[2];
+        let server_url = &url(&args[2]);
         // parse integer from args
         let file_index = args[3].parse::<usize>().unwrap();
-        download_verify_file(server_url, file_index);
+        download_verify_file(server_url, file_index, proof_encoding, server_key.as_ref());
+    } else if args.len() == 6 && args[1] == "download-range" {
+        let server_url = &url(&args[2]);
+        let file_index = args[3].parse::<usize>().unwrap();
+        let offset = args[4].parse::<u64>().unwrap();
+        let length = args[5].parse::<u64>().unwrap();
+        download_verify_range(
+            server_url,
+            file_index,
+            offset,
+            length,
+            proof_encoding,
+            server_key.as_ref(),
+        );
+    } else if args.len() == 4 && args[1] == "download-name" {
+        let server_url = &url(&args[2]);
+        let name = &args[3];
+        download_verify_named_file(server_url, name);
+    } else if args.len() >= 4 && args[1] == "audit" {
+        let server_url = &url(&args[2]);
+        audit_absent_files(server_url, &args[3..]);
+    } else if args.len() >= 5 && args[1] == "download-batch" {
+        let server_url = &url(&args[2]);
+        let out_dir = &args[3];
+        let file_indices: Vec<usize> = args[4..]
+            .iter()
+            .map(|arg| arg.parse::<usize>().unwrap())
+            .collect();
+        download_verify_files(server_url, &file_indices, out_dir, server_key.as_ref());
+    } else if args.len() == 5 && args[1] == "restore" {
+        let server_url = &url(&args[2]);
+        let manifest_path = &args[3];
+        let out_dir = &args[4];
+        restore_dataset(
+            server_url,
+            manifest_path,
+            out_dir,
+            jobs,
+            proof_encoding,
+            &manifest_key(server_key),
+        );
     } else {
         show_usage();
     }
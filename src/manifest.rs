@@ -0,0 +1,183 @@
+use crate::entry::*;
+use crate::hasher::*;
+use crate::merkle::*;
+use crate::receipt::Receipt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+/// The version of the manifest format.
+/// The first one allowed the leaves to be the content hashes, which left the paths unchecked.
+pub const MANIFEST_VERSION: u8 = 2;
+
+/// What the client keeps after an upload: where the files are, the merkle root of the upload
+/// and the entry of every file in the index order, with the path, size and content hash of the file.
+///
+/// The leaves are the hashes of the entries, so the paths and sizes are hashed into the root,
+/// and the root is signed by the server in the receipt. The manifest is only accepted
+/// if its entries lead to the root and the receipt is signed with the key it's checked against.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub version: u8,
+    /// The URL of the server with the files.
+    pub server: String,
+    #[serde(with = "root_serde")]
+    pub root: MerkleRoot,
+    pub files: Vec<FileEntry>,
+    /// The server's signed receipt of the root, see [`Receipt`].
+    pub receipt: Receipt,
+}
+
+// Just the version, to tell a manifest of another version from an invalid one.
+#[derive(Deserialize)]
+struct ManifestVersion {
+    version: u8,
+}
+
+impl Manifest {
+    /// The manifest of the files on the server, with the root of their entries
+    /// in the tree of the root of the receipt.
+    pub fn new(server: &str, files: Vec<FileEntry>, receipt: Receipt) -> Self {
+        let MerkleRoot {
+            mode, algorithm, ..
+        } = receipt.root;
+        Manifest {
+            version: MANIFEST_VERSION,
+            server: server.to_string(),
+            root: calculate_root(&files, mode, algorithm),
+            files,
+            receipt,
+        }
+    }
+
+    /// Check that the files lead to the root, that every file has its own valid path
+    /// and that the receipt is a signature of the root with the given server key.
+    pub fn check(&self, server_key: &[u8; 32]) -> Result<(), String> {
+        if self.version != MANIFEST_VERSION {
+            return Err(format!("Unsupported manifest version {}", self.version));
+        }
+        check_entry_mode(self.root.mode)?;
+        let mut paths = HashSet::new();
+        for file in &self.files {
+            check_path(&file.path)?;
+            if !paths.insert(&file.path) {
+                return Err(format!("Duplicate path {}", file.path));
+            }
+        }
+        let root = calculate_root(&self.files, self.root.mode, self.root.algorithm);
+        if root != self.root {
+            return Err(format!(
+                "The files of the manifest lead to {}, not to its root {}",
+                root, self.root
+            ));
+        }
+        self.receipt.verify(Some(server_key))?;
+        self.receipt.check(&self.root)
+    }
+
+    /// The index and the entry of the file with the given path.
+    pub fn find(&self, path: &str) -> Option<(usize, &FileEntry)> {
+        self.files
+            .iter()
+            .enumerate()
+            .find(|(_, file)| file.path == path)
+    }
+
+    /// Read the manifest at the given path and check it with the given server key.
+    pub fn load<P: AsRef<Path>>(path: P, server_key: &[u8; 32]) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| e.to_string())?;
+        let invalid = |e: serde_json::Error| format!("Invalid manifest: {}", e);
+        let ManifestVersion { version } = serde_json::from_slice(&bytes).map_err(invalid)?;
+        if version != MANIFEST_VERSION {
+            return Err(format!("Unsupported manifest version {}", version));
+        }
+        let manifest: Manifest = serde_json::from_slice(&bytes).map_err(invalid)?;
+        manifest.check(server_key)?;
+        Ok(manifest)
+    }
+
+    /// Write the manifest to the given path as JSON.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let json = serde_json::to_vec_pretty(self).expect("manifest is valid JSON");
+        fs::write(path, json).map_err(|e| e.to_string())
+    }
+}
+
+// The root of the entries of the files.
+fn calculate_root(files: &[FileEntry], mode: TreeMode, algorithm: HashAlgorithm) -> MerkleRoot {
+    let mut builder = MerkleRootBuilder::new(mode, algorithm);
+    for file in files {
+        builder.push(file.leaf(&algorithm));
+    }
+    builder.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::*;
+    use ed25519_dalek::SigningKey;
+
+    fn files() -> Vec<FileEntry> {
+        (0..5u8)
+            .map(|i| FileEntry {
+                path: format!("dir/f{}", i),
+                size: i as u64,
+                permissions: None,
+                mtime: None,
+                hash: [i; 32],
+                symlink: false,
+            })
+            .collect()
+    }
+
+    // The manifest of the files signed with the key, and the public key.
+    fn signed_manifest(key: &SigningKey) -> (Manifest, [u8; 32]) {
+        let root = calculate_root(&files(), TreeMode::Rfc6962, HashAlgorithm::Blake3);
+        let receipt = Receipt::sign("default", root, key);
+        let manifest = Manifest::new("http://localhost:8080", files(), receipt);
+        (manifest, key.verifying_key().to_bytes())
+    }
+
+    #[test]
+    fn manifests_are_checked_against_their_root() {
+        let (manifest, key) = signed_manifest(&SigningKey::from_bytes(&[7; 32]));
+        assert_eq!(manifest.check(&key), Ok(()));
+        assert_eq!(manifest.root.leaf_count, 5);
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(serde_json::from_str::<Manifest>(&json).unwrap(), manifest);
+        assert_eq!(manifest.find("dir/f3").map(|(index, _)| index), Some(3));
+        // the paths, sizes and hashes are all in the root
+        let mut renamed = manifest.clone();
+        renamed.files[1].path = "dir/other".to_string();
+        assert!(renamed.check(&key).is_err());
+        let mut resized = manifest.clone();
+        resized.files[1].size = 9;
+        assert!(resized.check(&key).is_err());
+        let mut changed = manifest.clone();
+        changed.files[1].hash = [9; 32];
+        assert!(changed.check(&key).is_err());
+        let mut duplicate = manifest;
+        duplicate.files[1].path = "dir/f0".to_string();
+        assert!(duplicate.check(&key).is_err());
+    }
+
+    #[test]
+    fn receipts_of_manifests_are_checked() {
+        let (manifest, key) = signed_manifest(&SigningKey::from_bytes(&[7; 32]));
+        // a manifest signed by another key is no manifest of the pinned server,
+        // even if it's consistent in itself
+        let (other, other_key) = signed_manifest(&SigningKey::from_bytes(&[8; 32]));
+        assert_eq!(other.check(&other_key), Ok(()));
+        assert!(other.check(&key).is_err());
+        let mut forged = manifest.clone();
+        forged.receipt.timestamp += 1;
+        assert!(forged.check(&key).is_err());
+        // the receipt of another root
+        let mut renamed = manifest;
+        renamed.files[1].path = "dir/other".to_string();
+        renamed.root = calculate_root(&renamed.files, renamed.root.mode, renamed.root.algorithm);
+        assert!(renamed.check(&key).is_err());
+    }
+}
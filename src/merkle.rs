@@ -1,20 +1,21 @@
-use sha2::Digest;
-use sha2::Sha256;
+use crate::hasher::*;
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use std::fmt;
 use std::fs;
-use std::fs::File;
 use std::io;
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
 
 // We need to get the files in some order to ensure that the merkle root is always the same.
 // There are two ways to do this:
 // 1. Sort the files by name.
-//    TODO: ensure that this is the same on all platforms considering Unicode names (normalization, ordering, etc.)
 // 2. Calculate the hash of each file content and sort by hash.
 //    This is name-independent approach, but it is slower as it requires two passes over the files.
 // Here we use the first approach.
-// We read the current working directory and sort the files by name.
+// We read the directory and sort the files by name, which is enough for the files the server names by their index.
+// Uploads walk their directory with `walk::walk_dir`, which orders the files the same on all platforms.
 pub fn list_files_in_order<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
     let mut files = Vec::new();
     let mut paths: Vec<_> = fs::read_dir(dir).map(|rd| rd.map(|dir| dir.unwrap()).collect())?;
@@ -32,13 +33,72 @@ pub fn list_files_in_order<P: AsRef<Path>>(dir: P) -> io::Result<Vec<PathBuf>> {
     Ok(files)
 }
 
-pub fn hash_file_by_path<P: AsRef<Path>>(path: P) -> io::Result<[u8; 32]> {
-    let mut file = File::open(path)?;
-    let mut hasher = Sha256::new();
-    // TODO: use buffered reader if needed
-    io::copy(&mut file, &mut hasher)?;
-    let hash = hasher.finalize();
-    Ok(hash.into())
+/// A Merkle root together with the hash algorithm, the mode and the number of leaves of the tree.
+/// Formatted as `<algorithm>:<mode>:<leaf count>:<hex root>`, e.g. `sha256:rfc6962:3:9f86d0...`.
+/// Bare hex roots of the first version aren't accepted: their trees duplicated the last node of odd levels,
+/// so with more than one file, unless the number is a power of two, no tree built now has the same root.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MerkleRoot {
+    pub algorithm: HashAlgorithm,
+    pub mode: TreeMode,
+    pub leaf_count: usize,
+    pub hash: [u8; 32],
+}
+
+impl fmt::Display for MerkleRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}:{}",
+            self.algorithm,
+            self.mode,
+            self.leaf_count,
+            hex_hash(&self.hash)
+        )
+    }
+}
+
+impl FromStr for MerkleRoot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.trim().split(':').collect();
+        let [algorithm, mode, leaf_count, hex_root] = fields[..] else {
+            return Err(format!(
+                "Merkle root must be <algorithm>:<mode>:<leaf count>:<hex root>, but got: {}",
+                s.trim()
+            ));
+        };
+        let leaf_count = leaf_count
+            .parse()
+            .map_err(|e| format!("Invalid leaf count {}: {}", leaf_count, e))?;
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(hex_root, &mut hash)
+            .map_err(|e| format!("Invalid hex string for merkle root: {}", e))?;
+        Ok(MerkleRoot {
+            algorithm: algorithm.parse()?,
+            mode: mode.parse()?,
+            leaf_count,
+            hash,
+        })
+    }
+}
+
+/// Serialize a merkle root as its string, see [`MerkleRoot`].
+pub mod root_serde {
+    use super::MerkleRoot;
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(root: &MerkleRoot, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&root.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<MerkleRoot, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
 }
 
 /// Convert a hash to a hex string.
@@ -48,11 +108,14 @@ pub fn hex_hash(hash: &[u8; 32]) -> String {
         .collect::<String>()
 }
 
-pub struct MerkleTree {
+pub struct MerkleTree<H: MerkleHasher = HashAlgorithm> {
+    hasher: H,
+    mode: TreeMode,
+    leaf_count: usize,
     levels: Vec<Vec<[u8; 32]>>,
 }
 
-impl MerkleTree {
+impl<H: MerkleHasher> MerkleTree<H> {
     // This is a naive and simple implementation of the merkle tree calculation.
     // It requires all the files' hashes in memory.
     // Each hash is 32 bytes, so even for millions of files it is not a huge problem though.
@@ -60,21 +123,32 @@ impl MerkleTree {
 
     // This approach gives us ability to reuse the Merkle tree for
     // both Merkle Root calculation and Merkle Proof generation.
-    pub fn from_hashes(hashes: Vec<[u8; 32]>) -> Self {
+    pub fn from_hashes(hashes: Vec<[u8; 32]>, mode: TreeMode, hasher: H) -> Self {
         let mut levels = Vec::<Vec<[u8; 32]>>::new();
+        let leaf_count = hashes.len();
 
-        if hashes.len() == 0 {
-            levels.push(vec![[0u8; 32]]);
-            return MerkleTree { levels };
+        if hashes.is_empty() {
+            levels.push(vec![empty_root(&hasher, mode)]);
+            return MerkleTree {
+                hasher,
+                mode,
+                leaf_count,
+                levels,
+            };
         }
 
         if hashes.len() == 1 {
             levels.push(hashes);
-            return MerkleTree { levels };
+            return MerkleTree {
+                hasher,
+                mode,
+                leaf_count,
+                levels,
+            };
         }
         let mut level_hashes = hashes;
         loop {
-            let next_level_hashes = calculate_merkle_tree_level(&mut level_hashes);
+            let next_level_hashes = calculate_merkle_tree_level(&hasher, mode, &level_hashes);
             let level_size = next_level_hashes.len();
             levels.push(level_hashes);
             level_hashes = next_level_hashes;
@@ -83,7 +157,12 @@ impl MerkleTree {
                 break;
             }
         }
-        MerkleTree { levels }
+        MerkleTree {
+            hasher,
+            mode,
+            leaf_count,
+            levels,
+        }
     }
 
     /// Get the merkle root of the tree.
@@ -91,26 +170,86 @@ impl MerkleTree {
         &self.levels.last().unwrap()[0]
     }
 
+    /// Get the merkle root of the tree together with its mode and leaf count.
+    pub fn root(&self) -> MerkleRoot {
+        MerkleRoot {
+            algorithm: self.hasher.algorithm(),
+            mode: self.mode,
+            leaf_count: self.leaf_count,
+            hash: *self.get_merkle_root(),
+        }
+    }
+
+    /// Get all the nodes level by level, from the leaves to the root.
+    /// An empty tree has a single level with the empty root.
+    pub fn levels(&self) -> &[Vec<[u8; 32]>] {
+        &self.levels
+    }
+
+    /// Get the leaf hashes in the index order.
+    pub fn leaves(&self) -> &[[u8; 32]] {
+        &self.levels[0][..self.leaf_count]
+    }
+
     /// Get the merkle proof for the leaf with the given index.
+    /// The server proves from the tree file instead, see [`MappedTree::make_proof`](crate::tree_file::MappedTree::make_proof).
+    #[cfg(test)]
     pub fn make_merkle_proof(&self, index: usize) -> Vec<[u8; 32]> {
         let proof_size = self.levels.len() - 1;
-        let hashes_count = self.levels[0].len();
-        assert!(index < hashes_count);
+        // an empty tree has the empty root as its only node, but no leaves
+        assert!(index < self.leaf_count);
         if proof_size == 0 {
             return vec![];
         }
-        let mut proof = Vec::with_capacity(proof_size - 1);
+        let mut proof = Vec::with_capacity(proof_size);
         for level in 0..proof_size {
             let level_hashes = &self.levels[level];
             let idx = index / 2usize.pow(level as u32);
-            let proof_hash_idx = if idx % 2 == 0 { idx + 1 } else { idx - 1 };
-            proof.push(level_hashes[proof_hash_idx]);
+            let proof_hash_idx = if idx.is_multiple_of(2) {
+                idx + 1
+            } else {
+                idx - 1
+            };
+            // the last node of an odd level is promoted and has no sibling
+            if proof_hash_idx < level_hashes.len() {
+                proof.push(level_hashes[proof_hash_idx]);
+            }
+        }
+        proof
+    }
+
+    /// Get a single merkle proof for all the leaves with the given indices.
+    /// Siblings shared by several leaves, or computable from the leaves themselves,
+    /// are included only once or not at all.
+    /// The siblings are ordered level by level, and by index within a level.
+    pub fn make_merkle_multiproof(&self, indices: &[usize]) -> Vec<[u8; 32]> {
+        let mut known = indices.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        assert!(known.iter().all(|&index| index < self.leaf_count));
+        let mut proof = Vec::new();
+        for level_hashes in &self.levels[..self.levels.len() - 1] {
+            let mut next_known = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let idx = known[i];
+                let sibling = idx ^ 1;
+                if known.get(i + 1) == Some(&sibling) {
+                    // both children are known, the parent can be computed
+                    i += 1;
+                } else if sibling < level_hashes.len() {
+                    proof.push(level_hashes[sibling]);
+                }
+                next_known.push(idx / 2);
+                i += 1;
+            }
+            known = next_known;
         }
         proof
     }
 }
 
-impl fmt::Display for MerkleTree {
+impl<H: MerkleHasher> fmt::Display for MerkleTree<H> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Write the formatted representation of the Merkle tree to the provided formatter
         let str = self
@@ -130,59 +269,434 @@ impl fmt::Display for MerkleTree {
     }
 }
 
-fn calculate_merkle_tree_level(hashes: &mut Vec<[u8; 32]>) -> Vec<[u8; 32]> {
-    let mut hasher = Sha256::new();
-    let mut level_hashes = Vec::with_capacity(hashes.len() / 2);
-    // duplicate last element if odd number of elements
-    // there is a potential problem, see https://github.com/bitcoin/bitcoin/blob/master/src/consensus/merkle.cpp#L8
-    // but it is not a problem in our case
-    if hashes.len() % 2 == 1 {
-        hashes.push(hashes.last().unwrap().clone());
-    }
-    for i in (0..hashes.len()).step_by(2) {
-        hasher.update(hashes[i]);
-        hasher.update(hashes[i + 1]);
-        let hash = hasher.finalize_reset().into();
-        level_hashes.push(hash);
-    }
-    level_hashes
+/// The root of a tree without leaves.
+/// RFC 6962 defines it as the hash of an empty string.
+pub fn empty_root<H: MerkleHasher>(hasher: &H, mode: TreeMode) -> [u8; 32] {
+    match mode {
+        TreeMode::Plain => [0u8; 32],
+        TreeMode::Rfc6962 => hasher.hash_leaf(TreeMode::Plain, &[]),
+    }
+}
+
+/// Computes the same root as [`MerkleTree::from_hashes`] from leaves pushed one by one,
+/// without keeping them in memory.
+///
+/// Only the roots of the perfect subtrees built so far are kept.
+/// Their sizes are the set bits of the leaf count, so there are at most log2(n) of them.
+/// Pushing a leaf merges the subtrees of equal size like a binary counter carry.
+/// Finalizing folds the remaining subtrees from the smallest to the largest,
+/// which is exactly what promoting the odd nodes level by level does.
+pub struct MerkleRootBuilder<H: MerkleHasher = HashAlgorithm> {
+    hasher: H,
+    mode: TreeMode,
+    leaf_count: usize,
+    // largest subtree first
+    subtrees: Vec<[u8; 32]>,
+}
+
+impl<H: MerkleHasher> MerkleRootBuilder<H> {
+    pub fn new(mode: TreeMode, hasher: H) -> Self {
+        MerkleRootBuilder {
+            hasher,
+            mode,
+            leaf_count: 0,
+            subtrees: Vec::new(),
+        }
+    }
+
+    /// Add the next leaf hash.
+    pub fn push(&mut self, leaf: [u8; 32]) {
+        let mut hash = leaf;
+        let mut count = self.leaf_count;
+        while count & 1 == 1 {
+            let left = self.subtrees.pop().unwrap();
+            hash = self.hasher.hash_node(self.mode, &left, &hash);
+            count >>= 1;
+        }
+        self.subtrees.push(hash);
+        self.leaf_count += 1;
+    }
+
+    /// Get the merkle root of all the pushed leaves.
+    pub fn finalize(self) -> MerkleRoot {
+        let mut subtrees = self.subtrees.iter().rev();
+        let hash = match subtrees.next() {
+            Some(smallest) => subtrees.fold(*smallest, |hash, left| {
+                self.hasher.hash_node(self.mode, left, &hash)
+            }),
+            None => empty_root(&self.hasher, self.mode),
+        };
+        MerkleRoot {
+            algorithm: self.hasher.algorithm(),
+            mode: self.mode,
+            leaf_count: self.leaf_count,
+            hash,
+        }
+    }
+}
+
+// Below this many nodes a level is hashed faster on one thread than split between threads.
+#[cfg(feature = "parallel")]
+const PARALLEL_LEVEL_SIZE: usize = 1 << 12;
+
+fn calculate_merkle_tree_level<H: MerkleHasher>(
+    hasher: &H,
+    mode: TreeMode,
+    hashes: &[[u8; 32]],
+) -> Vec<[u8; 32]> {
+    // The last element of an odd level is promoted to the next level unchanged.
+    // Duplicating it instead would make [a, b, c] and [a, b, c, c] share a root,
+    // see https://github.com/bitcoin/bitcoin/blob/master/src/consensus/merkle.cpp#L8
+    // This also gives the same tree shape as RFC 6962.
+    let parent = |pair: &[[u8; 32]]| match pair {
+        [left, right] => hasher.hash_node(mode, left, right),
+        [last] => *last,
+        _ => unreachable!(),
+    };
+    // pairs are independent and collected in order, so both ways give the same level
+    #[cfg(feature = "parallel")]
+    if hashes.len() >= PARALLEL_LEVEL_SIZE {
+        return hashes.par_chunks(2).map(parent).collect();
+    }
+    hashes.chunks(2).map(parent).collect()
+}
+
+/// Why a merkle proof of a single file failed verification.
+#[derive(Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// There is no file with the index in a tree of this size.
+    IndexOutOfRange { index: usize, leaf_count: usize },
+    /// The proof doesn't have exactly one sibling for every level where the node isn't promoted.
+    ProofLength { expected: usize, actual: usize },
+    /// The proof is well-formed, but leads to another root.
+    RootMismatch { calculated: [u8; 32] },
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::IndexOutOfRange { index, leaf_count } => write!(
+                f,
+                "File index {} is out of range, there are {} files",
+                index, leaf_count
+            ),
+            VerifyError::ProofLength { expected, actual } => {
+                write!(f, "Proof must have {} hashes, but has {}", expected, actual)
+            }
+            VerifyError::RootMismatch { calculated } => write!(
+                f,
+                "Calculated merkle root {} does not match",
+                hex_hash(calculated)
+            ),
+        }
+    }
+}
+
+/// The number of siblings in the merkle proof of the leaf with the given index:
+/// one for every level where the node isn't the promoted last node of an odd level.
+pub fn proof_length(index: usize, leaf_count: usize) -> usize {
+    let mut index = index;
+    let mut level_size = leaf_count;
+    let mut length = 0;
+    while level_size > 1 {
+        if index != level_size - 1 || level_size.is_multiple_of(2) {
+            length += 1;
+        }
+        index /= 2;
+        level_size = level_size.div_ceil(2);
+    }
+    length
 }
 
 /// Calculate merkle root from the hash of the file and the merkle proof.
-pub fn calculate_merkle_root_from_proof(
+/// The leaf count is needed to know at which levels the node was promoted without a sibling,
+/// so the proof must have exactly the siblings the leaf has in a tree of that size.
+pub fn calculate_merkle_root_from_proof<H: MerkleHasher>(
+    hasher: &H,
+    mode: TreeMode,
     index: usize,
+    leaf_count: usize,
     hash: &[u8; 32],
-    proof: &Vec<[u8; 32]>,
-) -> [u8; 32] {
+    proof: &[[u8; 32]],
+) -> Result<[u8; 32], VerifyError> {
+    if index >= leaf_count {
+        return Err(VerifyError::IndexOutOfRange { index, leaf_count });
+    }
+    let expected = proof_length(index, leaf_count);
+    if proof.len() != expected {
+        return Err(VerifyError::ProofLength {
+            expected,
+            actual: proof.len(),
+        });
+    }
     let mut index = index;
-    let mut hasher = Sha256::new();
-    let mut hash = hash.clone();
-    for sibling in proof {
-        if index % 2 == 0 {
-            hasher.update(hash);
-            hasher.update(sibling);
-        } else {
-            hasher.update(sibling);
-            hasher.update(hash);
+    let mut level_size = leaf_count;
+    let mut hash = *hash;
+    let mut siblings = proof.iter();
+    while level_size > 1 {
+        let promoted = index == level_size - 1 && !level_size.is_multiple_of(2);
+        if !promoted {
+            // there are as many siblings as levels with one
+            let sibling = siblings.next().unwrap();
+            if index.is_multiple_of(2) {
+                hash = hasher.hash_node(mode, &hash, sibling);
+            } else {
+                hash = hasher.hash_node(mode, sibling, &hash);
+            }
         }
-        hash = hasher.finalize_reset().into();
         index /= 2;
+        level_size = level_size.div_ceil(2);
     }
-    hash
+    Ok(hash)
 }
 
 /// Verify that the merkle root is correct for the given file hash and proof.
+/// The proof is checked with the algorithm, mode and leaf count recorded with the merkle root.
 pub fn verify_file(
-    merkle_root: &[u8; 32],
+    merkle_root: &MerkleRoot,
     file_index: usize,
     file_hash: &[u8; 32],
-    proof: &Vec<[u8; 32]>,
-) -> Result<(), [u8; 32]> {
-    let calculated_merkle_root = calculate_merkle_root_from_proof(file_index, file_hash, proof);
-    if calculated_merkle_root != *merkle_root {
-        return Err(calculated_merkle_root);
+    proof: &[[u8; 32]],
+) -> Result<(), VerifyError> {
+    let calculated = calculate_merkle_root_from_proof(
+        &merkle_root.algorithm,
+        merkle_root.mode,
+        file_index,
+        merkle_root.leaf_count,
+        file_hash,
+        proof,
+    )?;
+    if calculated != merkle_root.hash {
+        Err(VerifyError::RootMismatch { calculated })
+    } else {
+        Ok(())
+    }
+}
+
+/// Calculate merkle root from the hashes of several files and their multiproof,
+/// see [`MerkleTree::make_merkle_multiproof`].
+/// Returns `None` if the leaves are empty, out of range, contradict each other,
+/// or the proof doesn't have exactly the siblings they need.
+pub fn calculate_merkle_root_from_multiproof<H: MerkleHasher>(
+    hasher: &H,
+    mode: TreeMode,
+    leaf_count: usize,
+    leaves: &[(usize, [u8; 32])],
+    proof: &[[u8; 32]],
+) -> Option<[u8; 32]> {
+    let mut nodes = leaves.to_vec();
+    nodes.sort_unstable();
+    nodes.dedup();
+    if nodes.is_empty() || nodes.last()?.0 >= leaf_count {
+        return None;
+    }
+    if nodes.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return None;
+    }
+    let mut level_size = leaf_count;
+    let mut siblings = proof.iter();
+    while level_size > 1 {
+        let mut next_nodes = Vec::with_capacity(nodes.len());
+        let mut i = 0;
+        while i < nodes.len() {
+            let (idx, hash) = nodes[i];
+            let parent = if !idx.is_multiple_of(2) {
+                hasher.hash_node(mode, siblings.next()?, &hash)
+            } else if idx + 1 == level_size {
+                // promoted
+                hash
+            } else if nodes.get(i + 1).map(|node| node.0) == Some(idx + 1) {
+                i += 1;
+                hasher.hash_node(mode, &hash, &nodes[i].1)
+            } else {
+                hasher.hash_node(mode, &hash, siblings.next()?)
+            };
+            next_nodes.push((idx / 2, parent));
+            i += 1;
+        }
+        nodes = next_nodes;
+        level_size = level_size.div_ceil(2);
+    }
+    if siblings.next().is_some() {
+        return None;
+    }
+    Some(nodes[0].1)
+}
+
+/// Verify that the merkle root is correct for the given file hashes and multiproof.
+pub fn verify_files(
+    merkle_root: &MerkleRoot,
+    files: &[(usize, [u8; 32])],
+    proof: &[[u8; 32]],
+) -> Result<(), String> {
+    let calculated_merkle_root = calculate_merkle_root_from_multiproof(
+        &merkle_root.algorithm,
+        merkle_root.mode,
+        merkle_root.leaf_count,
+        files,
+        proof,
+    )
+    .ok_or_else(|| "Multiproof does not match the requested files".to_string())?;
+    if calculated_merkle_root != merkle_root.hash {
+        Err(format!(
+            "Calculated merkle root {} does not match the expected {}",
+            hex_hash(&calculated_merkle_root),
+            hex_hash(&merkle_root.hash)
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Verify that the new merkle root differs from the old one only by the leaf with the given index.
+/// The proof is the merkle proof of the leaf, which is the same in both trees
+/// as none of the siblings on its path changed.
+pub fn verify_update(
+    old_root: &MerkleRoot,
+    new_root: &MerkleRoot,
+    index: usize,
+    (old_hash, new_hash): (&[u8; 32], &[u8; 32]),
+    proof: &[[u8; 32]],
+) -> Result<(), String> {
+    if old_root.algorithm != new_root.algorithm
+        || old_root.mode != new_root.mode
+        || old_root.leaf_count != new_root.leaf_count
+    {
+        return Err(format!(
+            "Roots are for different trees: {} and {}",
+            old_root, new_root
+        ));
+    }
+    verify_file(old_root, index, old_hash, proof)
+        .map_err(|e| format!("Proof does not match the old file index {}: {}", index, e))?;
+    verify_file(new_root, index, new_hash, proof)
+        .map_err(|e| format!("Proof does not match the new file index {}: {}", index, e))
+}
+
+/// Get the RFC 6962 consistency proof that the tree of the first `old_size` leaves
+/// is a prefix of the tree of `leaf_count` leaves, with the hashes of the subtrees
+/// of the leaves in [start, end) given by `subtree_hash`.
+pub fn make_consistency_proof(
+    old_size: usize,
+    leaf_count: usize,
+    subtree_hash: impl Fn(usize, usize) -> [u8; 32],
+) -> Vec<[u8; 32]> {
+    assert!(old_size <= leaf_count);
+    let mut proof = Vec::new();
+    if old_size > 0 {
+        consistency_subproof(&subtree_hash, old_size, 0, leaf_count, true, &mut proof);
+    }
+    proof
+}
+
+// SUBPROOF(m, D[start:end], b) from RFC 6962 section 2.1.2
+fn consistency_subproof(
+    subtree_hash: &impl Fn(usize, usize) -> [u8; 32],
+    m: usize,
+    start: usize,
+    end: usize,
+    complete: bool,
+    proof: &mut Vec<[u8; 32]>,
+) {
+    let n = end - start;
+    if m == n {
+        if !complete {
+            proof.push(subtree_hash(start, end));
+        }
+        return;
+    }
+    // the largest power of two smaller than n
+    let k = 1 << (usize::BITS - 1 - (n - 1).leading_zeros());
+    if m <= k {
+        consistency_subproof(subtree_hash, m, start, start + k, complete, proof);
+        proof.push(subtree_hash(start + k, end));
+    } else {
+        consistency_subproof(subtree_hash, m - k, start + k, end, false, proof);
+        proof.push(subtree_hash(start, start + k));
+    }
+}
+
+/// Check an RFC 6962 consistency proof, i.e. that the tree with `old_size` leaves and `old_hash` root
+/// is a prefix of the tree with `new_size` leaves and `new_hash` root.
+/// This is the verification algorithm from RFC 9162 section 2.1.4.2.
+pub fn check_consistency_proof<H: MerkleHasher>(
+    hasher: &H,
+    mode: TreeMode,
+    (old_size, old_hash): (usize, &[u8; 32]),
+    (new_size, new_hash): (usize, &[u8; 32]),
+    proof: &[[u8; 32]],
+) -> bool {
+    if old_size > new_size {
+        return false;
+    }
+    if old_size == new_size {
+        return proof.is_empty() && old_hash == new_hash;
+    }
+    // every tree extends the empty tree
+    if old_size == 0 {
+        return proof.is_empty();
+    }
+    let mut path = proof.to_vec();
+    if old_size.is_power_of_two() {
+        path.insert(0, *old_hash);
+    }
+    let Some((first, rest)) = path.split_first() else {
+        return false;
+    };
+    let mut fn_ = old_size - 1;
+    let mut sn = new_size - 1;
+    while fn_ & 1 == 1 {
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    let mut fr = *first;
+    let mut sr = *first;
+    for c in rest {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            fr = hasher.hash_node(mode, c, &fr);
+            sr = hasher.hash_node(mode, c, &sr);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            sr = hasher.hash_node(mode, &sr, c);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    fr == *old_hash && sr == *new_hash && sn == 0
+}
+
+/// Verify that the tree of the old merkle root is a prefix of the tree of the new one,
+/// i.e. the new tree only appended leaves.
+pub fn verify_consistency(
+    old_root: &MerkleRoot,
+    new_root: &MerkleRoot,
+    proof: &[[u8; 32]],
+) -> Result<(), String> {
+    if old_root.algorithm != new_root.algorithm || old_root.mode != new_root.mode {
+        return Err(format!(
+            "Roots were built differently: {}:{} and {}:{}",
+            old_root.algorithm, old_root.mode, new_root.algorithm, new_root.mode
+        ));
+    }
+    if check_consistency_proof(
+        &new_root.algorithm,
+        new_root.mode,
+        (old_root.leaf_count, &old_root.hash),
+        (new_root.leaf_count, &new_root.hash),
+        proof,
+    ) {
+        Ok(())
     } else {
-        return Ok(());
+        Err(format!(
+            "Tree of {} files is not a prefix of the tree of {} files",
+            old_root.leaf_count, new_root.leaf_count
+        ))
     }
 }
 
@@ -193,30 +707,175 @@ mod tests {
 
     use proptest::prelude::*;
 
+    fn any_mode() -> impl Strategy<Value = TreeMode> {
+        prop_oneof![Just(TreeMode::Plain), Just(TreeMode::Rfc6962)]
+    }
+
+    fn any_algorithm() -> impl Strategy<Value = HashAlgorithm> {
+        prop::sample::select(HashAlgorithm::ALL.to_vec())
+    }
+
     proptest! {
         #[test]
-        fn all_proofs_are_valid(hashes in any::<Vec<[u8;32]>>()) {
+        fn all_proofs_are_valid(
+            hashes in any::<Vec<[u8;32]>>(),
+            mode in any_mode(),
+            algorithm in any_algorithm(),
+        ) {
             // create a merkle tree from random hashes
-            let mtree = MerkleTree::from_hashes(hashes.clone());
+            let mtree = MerkleTree::from_hashes(hashes.clone(), mode, algorithm);
             // for each hash, generate a proof and verify it
             for (index, hash) in hashes.iter().enumerate() {
               let proof = mtree.make_merkle_proof(index);
-              let root = calculate_merkle_root_from_proof(index, hash, &proof);
+              let root = calculate_merkle_root_from_proof(&algorithm, mode, index, hashes.len(), hash, &proof);
               // forall hashes, the merkle root from a proof should be the same as the merkle root of the tree
-              assert_eq!( root, *mtree.get_merkle_root() );
+              assert_eq!( root, Ok(*mtree.get_merkle_root()) );
               // forall hashes, verify_file should return Ok(())
               assert_eq!(
-                  verify_file(mtree.get_merkle_root(), index, hash, &proof),
+                  verify_file(&mtree.root(), index, hash, &proof),
                   Ok(())
               );
           }
         }
+
+        #[test]
+        fn malformed_proofs_are_rejected(
+            (hashes, index) in prop::collection::vec(any::<[u8;32]>(), 1..100).prop_flat_map(|hashes| {
+                let len = hashes.len();
+                (Just(hashes), 0..len)
+            }),
+            extra in any::<[u8;32]>(),
+        ) {
+            let mtree = MerkleTree::from_hashes(hashes.clone(), TreeMode::Rfc6962, Sha256Hasher);
+            let root = mtree.root();
+            let proof = mtree.make_merkle_proof(index);
+            assert_eq!(proof.len(), proof_length(index, hashes.len()));
+            // a sibling too many or too few
+            let mut extended = proof.clone();
+            extended.push(extra);
+            assert_eq!(
+                verify_file(&root, index, &hashes[index], &extended),
+                Err(VerifyError::ProofLength { expected: proof.len(), actual: proof.len() + 1 })
+            );
+            if let Some((_, truncated)) = proof.split_last() {
+                assert_eq!(
+                    verify_file(&root, index, &hashes[index], truncated),
+                    Err(VerifyError::ProofLength { expected: proof.len(), actual: proof.len() - 1 })
+                );
+            }
+            // no file at or past the count
+            assert_eq!(
+                verify_file(&root, hashes.len(), &hashes[index], &proof),
+                Err(VerifyError::IndexOutOfRange { index: hashes.len(), leaf_count: hashes.len() })
+            );
+            // nor against the root of a tree with another leaf count
+            let mut grown = hashes.clone();
+            grown.push(extra);
+            let grown = MerkleTree::from_hashes(grown, TreeMode::Rfc6962, Sha256Hasher).root();
+            assert!(verify_file(&grown, index, &hashes[index], &proof).is_err());
+        }
+
+        #[test]
+        fn merkle_root_string_roundtrip(
+            hash in any::<[u8;32]>(),
+            leaf_count in any::<usize>(),
+            mode in any_mode(),
+            algorithm in any_algorithm(),
+        ) {
+            let root = MerkleRoot { algorithm, mode, leaf_count, hash };
+            assert_eq!(root.to_string().parse::<MerkleRoot>(), Ok(root));
+        }
+
+        #[test]
+        fn root_builder_matches_tree(
+            hashes in any::<Vec<[u8;32]>>(),
+            mode in any_mode(),
+            algorithm in any_algorithm(),
+        ) {
+            let mut builder = MerkleRootBuilder::new(mode, algorithm);
+            for hash in &hashes {
+                builder.push(*hash);
+            }
+            let mtree = MerkleTree::from_hashes(hashes, mode, algorithm);
+            assert_eq!(builder.finalize(), mtree.root());
+        }
+
+        #[test]
+        fn multiproofs_are_valid(
+            (hashes, indices) in prop::collection::vec(any::<[u8;32]>(), 1..100).prop_flat_map(|hashes| {
+                let len = hashes.len();
+                (Just(hashes), prop::collection::vec(0..len, 1..10))
+            }),
+            mode in any_mode(),
+        ) {
+            let mtree = MerkleTree::from_hashes(hashes.clone(), mode, Sha256Hasher);
+            let proof = mtree.make_merkle_multiproof(&indices);
+            let mut leaves: Vec<_> = indices.iter().map(|&i| (i, hashes[i])).collect();
+            assert_eq!(verify_files(&mtree.root(), &leaves, &proof), Ok(()));
+            // never bigger than the separate proofs
+            let separate: usize = indices.iter().map(|&i| mtree.make_merkle_proof(i).len()).sum();
+            assert!(proof.len() <= separate);
+            // a tampered file is caught
+            leaves[0].1[0] ^= 1;
+            assert!(verify_files(&mtree.root(), &leaves, &proof).is_err());
+        }
+
+        #[test]
+        fn different_files_have_different_roots(
+            a in any::<Vec<Vec<u8>>>(),
+            b in any::<Vec<Vec<u8>>>(),
+        ) {
+            // only leaves hashed from files: a list of arbitrary hashes can hold the node of two others,
+            // which gives the same root in any mode
+            prop_assume!(a != b);
+            let root = |files: Vec<Vec<u8>>| {
+                let leaves = files
+                    .iter()
+                    .map(|file| Sha256Hasher.hash_leaf(TreeMode::Rfc6962, file))
+                    .collect();
+                *MerkleTree::from_hashes(leaves, TreeMode::Rfc6962, Sha256Hasher).get_merkle_root()
+            };
+            assert_ne!(root(a), root(b));
+        }
+
+        #[test]
+        fn repeated_tail_changes_root(
+            hashes in prop::collection::vec(any::<[u8;32]>(), 1..64),
+            tail_log in 0..6u32,
+            mode in any_mode(),
+        ) {
+            // repeating the last 2^k leaves is exactly what duplicating
+            // the last node of an odd level would hide
+            let tail = 2usize.pow(tail_log).min(hashes.len());
+            let mut extended = hashes.clone();
+            extended.extend_from_slice(&hashes[hashes.len() - tail..]);
+            let root = *MerkleTree::from_hashes(hashes, mode, Sha256Hasher).get_merkle_root();
+            let extended_root = *MerkleTree::from_hashes(extended, mode, Sha256Hasher).get_merkle_root();
+            assert_ne!(root, extended_root);
+        }
+    }
+
+    #[test]
+    fn parallel_hashing_is_deterministic() {
+        // enough leaves for the lower levels to be hashed in parallel with the `parallel` feature,
+        // and an odd count to have promoted nodes on the way up
+        let hashes: Vec<[u8; 32]> = (0..3 * 4096 + 5u32)
+            .map(|i| Blake3Hasher.hash_leaf(TreeMode::Rfc6962, &i.to_be_bytes()))
+            .collect();
+        for mode in [TreeMode::Plain, TreeMode::Rfc6962] {
+            let mut builder = MerkleRootBuilder::new(mode, Blake3Hasher);
+            for hash in &hashes {
+                builder.push(*hash);
+            }
+            let mtree = MerkleTree::from_hashes(hashes.clone(), mode, Blake3Hasher);
+            assert_eq!(mtree.root(), builder.finalize());
+        }
     }
 
     #[test]
     fn merkle_tree_root_on_empty_hashes() {
         let hashes: Vec<[u8; 32]> = Vec::new();
-        let mtree = MerkleTree::from_hashes(hashes);
+        let mtree = MerkleTree::from_hashes(hashes, TreeMode::Plain, Sha256Hasher);
         let root = mtree.get_merkle_root();
         assert_eq!(root, &[0u8; 32])
     }
@@ -226,7 +885,7 @@ mod tests {
         let hash: [u8; 32] =
             hex!("1d26c74fd25a4c3dbb09e029fc609588da499fd4af2a41c88f6316c7f8c54cf1");
         let hashes = vec![hash];
-        let mtree = MerkleTree::from_hashes(hashes);
+        let mtree = MerkleTree::from_hashes(hashes, TreeMode::Plain, Sha256Hasher);
         let root = mtree.get_merkle_root();
         assert_eq!(root, &hash)
     }
@@ -241,21 +900,84 @@ mod tests {
             hex!("c5fbbae0208e0c69e6f28fddce5b3770141c405f50100f666dce23c110090345"),
             hex!("dcbccb66ce7ebd666ce5837ce9d73df56049538623e4492ad6b98b37de9751ac"),
         ];
-        let mtree = MerkleTree::from_hashes(hashes.clone());
+        let mtree = MerkleTree::from_hashes(hashes.clone(), TreeMode::Plain, Sha256Hasher);
         assert_eq!(
             *mtree.get_merkle_root(),
-            hex!("909f4133d05851b483a924b2f3b565651a59efc2ecfcf522c161e446f9638a74")
+            hex!("1c915a98e59b51a712e3d6f39e0502001dea48eeea077b0255c771f7868cc8ed")
         );
         for (index, hash) in hashes.iter().enumerate() {
             let proof = mtree.make_merkle_proof(index);
-            let root = calculate_merkle_root_from_proof(index, hash, &proof);
+            let root = calculate_merkle_root_from_proof(
+                &Sha256Hasher,
+                TreeMode::Plain,
+                index,
+                hashes.len(),
+                hash,
+                &proof,
+            );
             assert_eq!(
                 root,
-                hex!("909f4133d05851b483a924b2f3b565651a59efc2ecfcf522c161e446f9638a74")
+                Ok(hex!(
+                    "1c915a98e59b51a712e3d6f39e0502001dea48eeea077b0255c771f7868cc8ed"
+                ))
             );
+            assert_eq!(verify_file(&mtree.root(), index, hash, &proof), Ok(()));
+        }
+    }
+
+    #[test]
+    fn rfc6962_nodes_are_not_leaves() {
+        let a = Sha256Hasher.hash_leaf(TreeMode::Rfc6962, b"a");
+        let b = Sha256Hasher.hash_leaf(TreeMode::Rfc6962, b"b");
+        let mtree = MerkleTree::from_hashes(vec![a, b], TreeMode::Rfc6962, Sha256Hasher);
+        // the concatenation of the two leaves can't be passed off as a single leaf
+        let forged_leaf = Sha256Hasher.hash_leaf(TreeMode::Rfc6962, &[a, b].concat());
+        assert_ne!(forged_leaf, *mtree.get_merkle_root());
+        // while in plain mode it can
+        let plain = MerkleTree::from_hashes(vec![a, b], TreeMode::Plain, Sha256Hasher);
+        let forged_leaf = Sha256Hasher.hash_leaf(TreeMode::Plain, &[a, b].concat());
+        assert_eq!(forged_leaf, *plain.get_merkle_root());
+    }
+
+    #[test]
+    fn multiproof_shares_siblings() {
+        let hashes: Vec<[u8; 32]> = (0..8u8).map(|i| [i; 32]).collect();
+        let mtree = MerkleTree::from_hashes(hashes.clone(), TreeMode::Rfc6962, Sha256Hasher);
+        // 0 and 1 are siblings, the only other hashes needed are the roots of [2, 3] and [4..8]
+        let proof = mtree.make_merkle_multiproof(&[1, 0]);
+        assert_eq!(proof.len(), 2);
+        let leaves = [(0, hashes[0]), (1, hashes[1])];
+        assert_eq!(verify_files(&mtree.root(), &leaves, &proof), Ok(()));
+        // a proof with extra hashes is rejected
+        let mut longer = proof.clone();
+        longer.push([0u8; 32]);
+        assert!(verify_files(&mtree.root(), &leaves, &longer).is_err());
+    }
+
+    #[test]
+    fn merkle_root_requires_all_fields() {
+        let root = "1c915a98e59b51a712e3d6f39e0502001dea48eeea077b0255c771f7868cc8ed\n";
+        assert!(root.parse::<MerkleRoot>().is_err());
+        let root = "plain:6:1c915a98e59b51a712e3d6f39e0502001dea48eeea077b0255c771f7868cc8ed";
+        assert!(root.parse::<MerkleRoot>().is_err());
+        let root =
+            "sha256:plain:6:1c915a98e59b51a712e3d6f39e0502001dea48eeea077b0255c771f7868cc8ed\n";
+        assert_eq!(root.parse::<MerkleRoot>().unwrap().leaf_count, 6);
+    }
+
+    #[test]
+    fn odd_level_is_not_duplicated() {
+        let a = Sha256Hasher.hash_leaf(TreeMode::Rfc6962, b"a");
+        let b = Sha256Hasher.hash_leaf(TreeMode::Rfc6962, b"b");
+        let c = Sha256Hasher.hash_leaf(TreeMode::Rfc6962, b"c");
+        for mode in [TreeMode::Plain, TreeMode::Rfc6962] {
+            let three = MerkleTree::from_hashes(vec![a, b, c], mode, Sha256Hasher);
+            let four = MerkleTree::from_hashes(vec![a, b, c, c], mode, Sha256Hasher);
+            assert_ne!(three.get_merkle_root(), four.get_merkle_root());
+            // c is promoted to the second level, so its proof is a single hash
             assert_eq!(
-                verify_file(mtree.get_merkle_root(), index, hash, &proof),
-                Ok(())
+                three.make_merkle_proof(2),
+                vec![Sha256Hasher.hash_node(mode, &a, &b)]
             );
         }
     }
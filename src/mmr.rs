@@ -0,0 +1,288 @@
+use crate::hasher::*;
+use crate::merkle::*;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// A Merkle Mountain Range: a list of perfect Merkle trees, the peaks,
+/// one for every set bit of the leaf count, from the largest to the smallest.
+///
+/// All the nodes are kept in one append-only list in post-order, so appending a leaf
+/// only adds the leaf and the O(log n) parents it completes, and the nodes of any older size
+/// are a prefix of the current ones. That's what gives proofs against any historical size,
+/// and what makes it cheap to persist.
+///
+/// The peaks are bagged from the right, `H(p1, H(p2, p3))`, which gives exactly the root
+/// and the proofs of a [`MerkleTree`] with the same leaves.
+pub struct MerkleMountainRange<H: MerkleHasher = HashAlgorithm> {
+    hasher: H,
+    mode: TreeMode,
+    leaf_count: usize,
+    nodes: Vec<[u8; 32]>,
+}
+
+// A perfect subtree with 2^height leaves has this many nodes.
+fn subtree_node_count(height: u32) -> usize {
+    (1 << (height + 1)) - 1
+}
+
+// The number of leaves of a range with the given number of nodes, if there is such a range.
+fn leaf_count(node_count: usize) -> Option<usize> {
+    let mut leaf_count = 0;
+    let mut remaining = node_count;
+    for height in (0..usize::BITS - 1).rev() {
+        if remaining >= subtree_node_count(height) {
+            remaining -= subtree_node_count(height);
+            leaf_count += 1 << height;
+        }
+    }
+    (remaining == 0).then_some(leaf_count)
+}
+
+// A peak: the height of its subtree, the position of its first node and of its first leaf.
+struct Peak {
+    height: u32,
+    first_node: usize,
+    first_leaf: usize,
+}
+
+impl Peak {
+    fn position(&self) -> usize {
+        self.first_node + subtree_node_count(self.height) - 1
+    }
+}
+
+// The peaks of a range with the given number of leaves, from the largest to the smallest.
+fn peaks(leaf_count: usize) -> Vec<Peak> {
+    let mut peaks = Vec::new();
+    let mut first_node = 0;
+    let mut first_leaf = 0;
+    for height in (0..usize::BITS).rev() {
+        if leaf_count & (1 << height) != 0 {
+            peaks.push(Peak {
+                height,
+                first_node,
+                first_leaf,
+            });
+            first_node += subtree_node_count(height);
+            first_leaf += 1 << height;
+        }
+    }
+    peaks
+}
+
+impl<H: MerkleHasher> MerkleMountainRange<H> {
+    pub fn new(mode: TreeMode, hasher: H) -> Self {
+        MerkleMountainRange {
+            hasher,
+            mode,
+            leaf_count: 0,
+            nodes: Vec::new(),
+        }
+    }
+
+    /// Load the range persisted with [`MerkleMountainRange::append_persisted`],
+    /// a file with all the nodes in post-order.
+    pub fn load<P: AsRef<Path>>(path: P, mode: TreeMode, hasher: H) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if !bytes.len().is_multiple_of(32) {
+            return Err(invalid(format!(
+                "Merkle mountain range size is not a multiple of 32: {}",
+                bytes.len()
+            )));
+        }
+        let nodes: Vec<[u8; 32]> = bytes
+            .chunks(32)
+            .map(|node| node.try_into().unwrap())
+            .collect();
+        let leaf_count = leaf_count(nodes.len()).ok_or_else(|| {
+            invalid(format!(
+                "{} nodes is not a valid merkle mountain range",
+                nodes.len()
+            ))
+        })?;
+        Ok(MerkleMountainRange {
+            hasher,
+            mode,
+            leaf_count,
+            nodes,
+        })
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.leaf_count
+    }
+
+    /// Append a leaf, merging the peaks of equal height it completes.
+    pub fn append(&mut self, leaf: [u8; 32]) {
+        self.nodes.push(leaf);
+        let mut height = 0;
+        // like a binary counter carry, every set low bit of the count is a peak to merge with
+        while self.leaf_count & (1 << height) != 0 {
+            let right = self.nodes.len() - 1;
+            let left = right - subtree_node_count(height);
+            let parent = self
+                .hasher
+                .hash_node(self.mode, &self.nodes[left], &self.nodes[right]);
+            self.nodes.push(parent);
+            height += 1;
+        }
+        self.leaf_count += 1;
+    }
+
+    /// Check that the range holds exactly the range of these leaves, node by node.
+    pub fn matches(&self, leaves: &[[u8; 32]]) -> bool {
+        let mut range = MerkleMountainRange::new(self.mode, &self.hasher);
+        for leaf in leaves {
+            range.append(*leaf);
+        }
+        range.nodes == self.nodes
+    }
+
+    /// Write all the nodes to the file at the given path, replacing it.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let nodes: Vec<u8> = self.nodes.iter().flatten().copied().collect();
+        fs::write(path, nodes)
+    }
+
+    /// Append a leaf and write the new nodes to the end of the file at the given path.
+    pub fn append_persisted<P: AsRef<Path>>(&mut self, path: P, leaf: [u8; 32]) -> io::Result<()> {
+        let old_node_count = self.nodes.len();
+        self.append(leaf);
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let new_nodes: Vec<u8> = self.nodes[old_node_count..]
+            .iter()
+            .flatten()
+            .copied()
+            .collect();
+        file.write_all(&new_nodes)?;
+        file.sync_all()
+    }
+
+    // Bag the peaks from the right.
+    fn bag(&self, peaks: &[Peak]) -> Option<[u8; 32]> {
+        peaks
+            .iter()
+            .rev()
+            .map(|peak| self.nodes[peak.position()])
+            .reduce(|right, left| self.hasher.hash_node(self.mode, &left, &right))
+    }
+
+    /// Get the merkle root the range had when it had the given number of leaves.
+    pub fn root(&self, size: usize) -> Option<MerkleRoot> {
+        if size > self.leaf_count {
+            return None;
+        }
+        Some(MerkleRoot {
+            algorithm: self.hasher.algorithm(),
+            mode: self.mode,
+            leaf_count: size,
+            hash: self
+                .bag(&peaks(size))
+                .unwrap_or_else(|| empty_root(&self.hasher, self.mode)),
+        })
+    }
+
+    /// Get the merkle proof of the leaf with the given index
+    /// against the root the range had when it had the given number of leaves.
+    pub fn make_proof(&self, index: usize, size: usize) -> Option<Vec<[u8; 32]>> {
+        if index >= size || size > self.leaf_count {
+            return None;
+        }
+        let peaks = peaks(size);
+        let peak_index = peaks
+            .iter()
+            .position(|peak| index < peak.first_leaf + (1 << peak.height))?;
+        let peak = &peaks[peak_index];
+        // descend from the peak to the leaf, the siblings are collected top-down
+        let mut proof = Vec::with_capacity(peak.height as usize + peaks.len());
+        let mut first_node = peak.first_node;
+        let mut first_leaf = peak.first_leaf;
+        for height in (0..peak.height).rev() {
+            let left_root = first_node + subtree_node_count(height) - 1;
+            let right_root = left_root + subtree_node_count(height);
+            if index < first_leaf + (1 << height) {
+                proof.push(self.nodes[right_root]);
+            } else {
+                proof.push(self.nodes[left_root]);
+                first_node = left_root + 1;
+                first_leaf += 1 << height;
+            }
+        }
+        proof.reverse();
+        // then the bag of the smaller peaks on the right and the larger peaks on the left, nearest first
+        proof.extend(self.bag(&peaks[peak_index + 1..]));
+        proof.extend(
+            peaks[..peak_index]
+                .iter()
+                .rev()
+                .map(|peak| self.nodes[peak.position()]),
+        );
+        Some(proof)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mmr::*;
+    use proptest::prelude::*;
+
+    // The number of nodes of a range with the given number of leaves.
+    fn node_count(leaf_count: usize) -> usize {
+        2 * leaf_count - leaf_count.count_ones() as usize
+    }
+
+    proptest! {
+        #[test]
+        fn historical_proofs_match_tree(hashes in prop::collection::vec(any::<[u8;32]>(), 0..40)) {
+            let mut mmr = MerkleMountainRange::new(TreeMode::Rfc6962, Sha256Hasher);
+            for hash in &hashes {
+                mmr.append(*hash);
+            }
+            assert_eq!(mmr.nodes.len(), node_count(hashes.len()));
+            for size in 0..=hashes.len() {
+                let tree = MerkleTree::from_hashes(hashes[..size].to_vec(), TreeMode::Rfc6962, Sha256Hasher);
+                assert_eq!(mmr.root(size), Some(tree.root()));
+                for (index, hash) in hashes[..size].iter().enumerate() {
+                    let proof = mmr.make_proof(index, size).unwrap();
+                    assert_eq!(proof, tree.make_merkle_proof(index));
+                    assert_eq!(verify_file(&tree.root(), index, hash, &proof), Ok(()));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn leaf_count_from_node_count() {
+        for leaf_count in 0..100 {
+            assert_eq!(super::leaf_count(node_count(leaf_count)), Some(leaf_count));
+        }
+        // 2 nodes can't be a range, the second leaf always brings its parent
+        assert_eq!(super::leaf_count(2), None);
+    }
+
+    #[test]
+    fn persisted_range_loads() {
+        let path = std::env::temp_dir().join(format!("mermade-mmr-{}", std::process::id()));
+        let mut mmr = MerkleMountainRange::new(TreeMode::Plain, Blake3Hasher);
+        for i in 0..11u8 {
+            mmr.append_persisted(&path, [i; 32]).unwrap();
+        }
+        let loaded = MerkleMountainRange::load(&path, TreeMode::Plain, Blake3Hasher).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.leaf_count(), 11);
+        assert_eq!(loaded.root(11), mmr.root(11));
+        assert_eq!(loaded.make_proof(3, 7), mmr.make_proof(3, 7));
+        let leaves: Vec<[u8; 32]> = (0..11u8).map(|i| [i; 32]).collect();
+        assert!(loaded.matches(&leaves));
+        assert!(!loaded.matches(&leaves[..10]));
+        // a changed inner node leaves the root as it is, but not the proofs
+        let mut changed = loaded;
+        changed.nodes[2] = [0; 32];
+        assert_eq!(changed.root(11), mmr.root(11));
+        assert!(!changed.matches(&leaves));
+    }
+}
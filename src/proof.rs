@@ -0,0 +1,522 @@
+use crate::hasher::*;
+use crate::merkle::{hex_hash, MerkleRoot};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// The first bytes of every framed proof.
+pub const MAGIC: [u8; 4] = *b"MRKP";
+/// The version of the framed proof format.
+pub const VERSION: u8 = 1;
+
+// magic, version, algorithm, mode, leaf index, leaf count, sibling count
+const HEADER_SIZE: usize = 4 + 1 + 1 + 1 + 8 + 8 + 4;
+
+/// A merkle proof of a single file that says what it proves.
+///
+/// Encoded as, integers big-endian:
+///
+/// ```text
+/// "MRKP" | version: u8 | algorithm id: u8 | mode id: u8 | leaf index: u64 | leaf count: u64
+///        | sibling count: u32 | siblings: sibling count * 32 bytes
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof {
+    pub algorithm: HashAlgorithm,
+    pub mode: TreeMode,
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProofError {
+    TooShort(usize),
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownAlgorithm(u8),
+    UnknownMode(u8),
+    /// The size doesn't match the sibling count of the header.
+    Length {
+        expected: usize,
+        actual: usize,
+    },
+    /// A number of the header doesn't fit in a `usize` on this platform.
+    TooLarge {
+        field: &'static str,
+        value: u64,
+    },
+    /// The size of a raw proof is not a multiple of 32.
+    RawSize(usize),
+    /// A JSON or CBOR proof can't be parsed.
+    Document(String),
+    /// The header doesn't match the proof that was asked for.
+    Mismatch {
+        field: &'static str,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl fmt::Display for ProofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofError::TooShort(size) => write!(f, "Proof of {} bytes is too short", size),
+            ProofError::BadMagic => write!(f, "Proof does not start with the magic bytes"),
+            ProofError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported proof version: {}", version)
+            }
+            ProofError::UnknownAlgorithm(id) => write!(f, "Unknown hash algorithm id: {}", id),
+            ProofError::UnknownMode(id) => write!(f, "Unknown tree mode id: {}", id),
+            ProofError::Length { expected, actual } => write!(
+                f,
+                "Proof must be {} bytes long, but got {}",
+                expected, actual
+            ),
+            ProofError::TooLarge { field, value } => {
+                write!(
+                    f,
+                    "Proof {} {} is too large for this platform",
+                    field, value
+                )
+            }
+            ProofError::RawSize(size) => write!(f, "Proof size is not a multiple of 32: {}", size),
+            ProofError::Document(e) => write!(f, "Invalid proof document: {}", e),
+            ProofError::Mismatch {
+                field,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Proof is for {} {}, but {} was expected",
+                field, actual, expected
+            ),
+        }
+    }
+}
+
+impl Proof {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + 32 * self.siblings.len());
+        bytes.extend_from_slice(&MAGIC);
+        bytes.push(VERSION);
+        bytes.push(self.algorithm.id());
+        bytes.push(self.mode.id());
+        bytes.extend_from_slice(&(self.leaf_index as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.leaf_count as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.siblings.len() as u32).to_be_bytes());
+        bytes.extend(self.siblings.iter().flatten());
+        bytes
+    }
+
+    /// Decode a framed proof. Anything but exactly one well-formed proof is an error.
+    pub fn decode(bytes: &[u8]) -> Result<Self, ProofError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ProofError::TooShort(bytes.len()));
+        }
+        if bytes[..4] != MAGIC {
+            return Err(ProofError::BadMagic);
+        }
+        if bytes[4] != VERSION {
+            return Err(ProofError::UnsupportedVersion(bytes[4]));
+        }
+        let algorithm =
+            HashAlgorithm::from_id(bytes[5]).ok_or(ProofError::UnknownAlgorithm(bytes[5]))?;
+        let mode = TreeMode::from_id(bytes[6]).ok_or(ProofError::UnknownMode(bytes[6]))?;
+        let to_usize = |field, value: u64| {
+            usize::try_from(value).map_err(|_| ProofError::TooLarge { field, value })
+        };
+        let leaf_index = to_usize(
+            "leaf index",
+            u64::from_be_bytes(bytes[7..15].try_into().unwrap()),
+        )?;
+        let leaf_count = to_usize(
+            "leaf count",
+            u64::from_be_bytes(bytes[15..23].try_into().unwrap()),
+        )?;
+        let sibling_count = u32::from_be_bytes(bytes[23..27].try_into().unwrap());
+        let expected = to_usize("sibling count", sibling_count.into())?
+            .checked_mul(32)
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .ok_or(ProofError::TooLarge {
+                field: "sibling count",
+                value: sibling_count.into(),
+            })?;
+        if bytes.len() != expected {
+            return Err(ProofError::Length {
+                expected,
+                actual: bytes.len(),
+            });
+        }
+        Ok(Proof {
+            algorithm,
+            mode,
+            leaf_index,
+            leaf_count,
+            siblings: decode_raw(&bytes[HEADER_SIZE..])?,
+        })
+    }
+
+    /// Check that this is the proof of the file with the given index in the tree of the given root.
+    pub fn check(&self, root: &MerkleRoot, index: usize) -> Result<(), ProofError> {
+        let mismatch = |field, expected: &dyn fmt::Display, actual: &dyn fmt::Display| {
+            Err(ProofError::Mismatch {
+                field,
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            })
+        };
+        if self.algorithm != root.algorithm {
+            return mismatch("algorithm", &root.algorithm, &self.algorithm);
+        }
+        if self.mode != root.mode {
+            return mismatch("mode", &root.mode, &self.mode);
+        }
+        if self.leaf_index != index {
+            return mismatch("file index", &index, &self.leaf_index);
+        }
+        if self.leaf_count != root.leaf_count {
+            return mismatch("file count", &root.leaf_count, &self.leaf_count);
+        }
+        Ok(())
+    }
+}
+
+/// How a proof is sent over the wire, chosen with the `Accept` header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProofEncoding {
+    /// The framed binary format of [`Proof::encode`].
+    #[default]
+    Binary,
+    /// A JSON document with hex hashes, for non-Rust tooling and humans.
+    Json,
+    /// The same document in CBOR, with the hashes as byte strings.
+    Cbor,
+}
+
+impl ProofEncoding {
+    pub const ALL: [ProofEncoding; 3] = [
+        ProofEncoding::Binary,
+        ProofEncoding::Json,
+        ProofEncoding::Cbor,
+    ];
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ProofEncoding::Binary => "application/octet-stream",
+            ProofEncoding::Json => "application/json",
+            ProofEncoding::Cbor => "application/cbor",
+        }
+    }
+
+    /// The encoding of a media type, ignoring its parameters, e.g. `application/json; charset=utf-8`.
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        ProofEncoding::ALL
+            .into_iter()
+            .find(|encoding| encoding.content_type().eq_ignore_ascii_case(media_type))
+    }
+}
+
+impl fmt::Display for ProofEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProofEncoding::Binary => write!(f, "binary"),
+            ProofEncoding::Json => write!(f, "json"),
+            ProofEncoding::Cbor => write!(f, "cbor"),
+        }
+    }
+}
+
+impl FromStr for ProofEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        ProofEncoding::ALL
+            .into_iter()
+            .find(|encoding| encoding.to_string() == s)
+            .ok_or_else(|| format!("Unknown proof encoding: {}", s))
+    }
+}
+
+// A hash as a hex string in human readable formats like JSON, and as a byte string in CBOR.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct WireHash([u8; 32]);
+
+impl Serialize for WireHash {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex_hash(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for WireHash {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = if deserializer.is_human_readable() {
+            hex::decode(String::deserialize(deserializer)?).map_err(D::Error::custom)?
+        } else {
+            serde_bytes::ByteBuf::deserialize(deserializer)?.into_vec()
+        };
+        let hash = bytes
+            .try_into()
+            .map_err(|bytes: Vec<u8>| D::Error::invalid_length(bytes.len(), &"32 bytes"))?;
+        Ok(WireHash(hash))
+    }
+}
+
+// The JSON and CBOR form of a proof. Unlike the binary one it carries the root the proof leads to.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProofDocument {
+    version: u8,
+    algorithm: String,
+    mode: String,
+    index: usize,
+    size: usize,
+    root: WireHash,
+    siblings: Vec<WireHash>,
+}
+
+impl Proof {
+    /// Encode the proof leading to the given root hash.
+    pub fn encode_as(&self, encoding: ProofEncoding, root_hash: &[u8; 32]) -> Vec<u8> {
+        if encoding == ProofEncoding::Binary {
+            return self.encode();
+        }
+        let document = ProofDocument {
+            version: VERSION,
+            algorithm: self.algorithm.to_string(),
+            mode: self.mode.to_string(),
+            index: self.leaf_index,
+            size: self.leaf_count,
+            root: WireHash(*root_hash),
+            siblings: self.siblings.iter().copied().map(WireHash).collect(),
+        };
+        match encoding {
+            ProofEncoding::Binary => unreachable!("binary proofs are not documents"),
+            ProofEncoding::Json => {
+                serde_json::to_vec_pretty(&document).expect("proof document is valid JSON")
+            }
+            ProofEncoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(&document, &mut bytes).expect("writing to memory can't fail");
+                bytes
+            }
+        }
+    }
+
+    // Decode a JSON or CBOR proof together with the root hash it leads to.
+    fn decode_document(
+        bytes: &[u8],
+        encoding: ProofEncoding,
+    ) -> Result<(Self, [u8; 32]), ProofError> {
+        let document: ProofDocument = match encoding {
+            ProofEncoding::Binary => unreachable!("binary proofs are not documents"),
+            ProofEncoding::Json => {
+                serde_json::from_slice(bytes).map_err(|e| ProofError::Document(e.to_string()))?
+            }
+            ProofEncoding::Cbor => {
+                ciborium::from_reader(bytes).map_err(|e| ProofError::Document(e.to_string()))?
+            }
+        };
+        if document.version != VERSION {
+            return Err(ProofError::UnsupportedVersion(document.version));
+        }
+        let proof = Proof {
+            algorithm: document.algorithm.parse().map_err(ProofError::Document)?,
+            mode: document.mode.parse().map_err(ProofError::Document)?,
+            leaf_index: document.index,
+            leaf_count: document.size,
+            siblings: document.siblings.into_iter().map(|hash| hash.0).collect(),
+        };
+        Ok((proof, document.root.0))
+    }
+}
+
+/// Decode the raw format: just the concatenated siblings.
+pub fn decode_raw(bytes: &[u8]) -> Result<Vec<[u8; 32]>, ProofError> {
+    if !bytes.len().is_multiple_of(32) {
+        return Err(ProofError::RawSize(bytes.len()));
+    }
+    Ok(bytes
+        .chunks(32)
+        .map(|hash| hash.try_into().unwrap())
+        .collect())
+}
+
+/// Decode the proof of the file with the given index in the tree of the given root
+/// and return its siblings.
+///
+/// Servers that predate the framed format send the raw siblings, which are accepted as they are.
+/// A raw proof starting with the magic bytes would be taken for a framed one,
+/// but that's a 2^-32 chance and it would only fail the verification.
+pub fn decode_proof(
+    bytes: &[u8],
+    encoding: ProofEncoding,
+    root: &MerkleRoot,
+    index: usize,
+) -> Result<Vec<[u8; 32]>, ProofError> {
+    let proof = match encoding {
+        ProofEncoding::Binary if !bytes.starts_with(&MAGIC) => return decode_raw(bytes),
+        ProofEncoding::Binary => Proof::decode(bytes)?,
+        ProofEncoding::Json | ProofEncoding::Cbor => {
+            let (proof, root_hash) = Proof::decode_document(bytes, encoding)?;
+            if root_hash != root.hash {
+                return Err(ProofError::Mismatch {
+                    field: "root",
+                    expected: hex_hash(&root.hash),
+                    actual: hex_hash(&root_hash),
+                });
+            }
+            proof
+        }
+    };
+    proof.check(root, index)?;
+    Ok(proof.siblings)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proof::*;
+    use proptest::prelude::*;
+
+    fn any_proof() -> impl Strategy<Value = Proof> {
+        (
+            prop::sample::select(HashAlgorithm::ALL.to_vec()),
+            prop::sample::select(vec![TreeMode::Plain, TreeMode::Rfc6962]),
+            any::<usize>(),
+            any::<usize>(),
+            prop::collection::vec(any::<[u8; 32]>(), 0..40),
+        )
+            .prop_map(
+                |(algorithm, mode, leaf_index, leaf_count, siblings)| Proof {
+                    algorithm,
+                    mode,
+                    leaf_index,
+                    leaf_count,
+                    siblings,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn proofs_roundtrip(proof in any_proof()) {
+            assert_eq!(Proof::decode(&proof.encode()), Ok(proof));
+        }
+
+        #[test]
+        fn truncated_proofs_are_rejected(proof in any_proof(), cut in 1..32usize) {
+            let bytes = proof.encode();
+            let cut = cut.min(bytes.len());
+            assert!(Proof::decode(&bytes[..bytes.len() - cut]).is_err());
+        }
+    }
+
+    #[test]
+    fn strict_parsing() {
+        let proof = Proof {
+            algorithm: HashAlgorithm::Blake3,
+            mode: TreeMode::Rfc6962,
+            leaf_index: 2,
+            leaf_count: 3,
+            siblings: vec![[1; 32], [2; 32]],
+        };
+        let bytes = proof.encode();
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(
+            Proof::decode(&trailing),
+            Err(ProofError::Length {
+                expected: bytes.len(),
+                actual: bytes.len() + 1
+            })
+        );
+        let mut version = bytes.clone();
+        version[4] = 2;
+        assert_eq!(
+            Proof::decode(&version),
+            Err(ProofError::UnsupportedVersion(2))
+        );
+        let mut algorithm = bytes.clone();
+        algorithm[5] = 0xff;
+        assert_eq!(
+            Proof::decode(&algorithm),
+            Err(ProofError::UnknownAlgorithm(0xff))
+        );
+    }
+
+    #[test]
+    fn raw_proofs_are_accepted() {
+        let root = MerkleRoot {
+            algorithm: HashAlgorithm::Sha256,
+            mode: TreeMode::Plain,
+            leaf_count: 3,
+            hash: [0; 32],
+        };
+        let raw = [[1u8; 32], [2u8; 32]].concat();
+        assert_eq!(
+            decode_proof(&raw, ProofEncoding::Binary, &root, 0),
+            Ok(vec![[1; 32], [2; 32]])
+        );
+        assert_eq!(
+            decode_proof(&raw[1..], ProofEncoding::Binary, &root, 0),
+            Err(ProofError::RawSize(63))
+        );
+        // a framed proof must be for the expected file
+        let framed = Proof {
+            algorithm: HashAlgorithm::Sha256,
+            mode: TreeMode::Plain,
+            leaf_index: 1,
+            leaf_count: 3,
+            siblings: vec![[1; 32]],
+        };
+        assert_eq!(
+            decode_proof(&framed.encode(), ProofEncoding::Binary, &root, 1),
+            Ok(vec![[1; 32]])
+        );
+        assert!(decode_proof(&framed.encode(), ProofEncoding::Binary, &root, 0).is_err());
+    }
+
+    #[test]
+    fn documents_roundtrip() {
+        let root = MerkleRoot {
+            algorithm: HashAlgorithm::Sha512_256,
+            mode: TreeMode::Rfc6962,
+            leaf_count: 5,
+            hash: [7; 32],
+        };
+        let proof = Proof {
+            algorithm: root.algorithm,
+            mode: root.mode,
+            leaf_index: 4,
+            leaf_count: 5,
+            siblings: vec![[1; 32], [2; 32]],
+        };
+        for encoding in ProofEncoding::ALL {
+            let bytes = proof.encode_as(encoding, &root.hash);
+            assert_eq!(
+                decode_proof(&bytes, encoding, &root, 4),
+                Ok(proof.siblings.clone())
+            );
+            let other_root = MerkleRoot {
+                hash: [8; 32],
+                ..root
+            };
+            if encoding != ProofEncoding::Binary {
+                // documents name their root
+                assert!(decode_proof(&bytes, encoding, &other_root, 4).is_err());
+            }
+        }
+        let json = String::from_utf8(proof.encode_as(ProofEncoding::Json, &root.hash)).unwrap();
+        assert!(json.contains(&hex_hash(&[1; 32])));
+        assert_eq!(
+            ProofEncoding::from_content_type("application/json; charset=utf-8"),
+            Some(ProofEncoding::Json)
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use crate::merkle::*;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+// The signed message starts with its own magic, so a receipt signature can't be passed off as anything else.
+const RECEIPT_MAGIC: &[u8; 4] = b"MRKR";
+const RECEIPT_VERSION: u8 = 1;
+
+/// The header proof responses carry the receipt of the root they lead to in, as JSON.
+pub const RECEIPT_HEADER: &str = "x-mermade-receipt";
+
+/// What the server answers when an upload is finalized: the root of its tree of the files of the dataset
+/// and how many files it has, signed with the server's Ed25519 key at the time of the upload.
+/// The client only keeps its own root if the server's is the same, and then the receipt is the server's word for it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Receipt {
+    pub dataset: String,
+    #[serde(with = "root_serde")]
+    pub root: MerkleRoot,
+    pub leaf_count: usize,
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    /// The public key of the server.
+    #[serde(with = "hex_bytes")]
+    pub key: [u8; 32],
+    #[serde(with = "hex_bytes")]
+    pub signature: [u8; 64],
+}
+
+mod hex_bytes {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        bytes: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let hex_string = String::deserialize(deserializer)?;
+        let mut bytes = [0u8; N];
+        hex::decode_to_slice(&hex_string, &mut bytes).map_err(D::Error::custom)?;
+        Ok(bytes)
+    }
+}
+
+impl Receipt {
+    /// The receipt of the root of the dataset, signed now with the given key.
+    pub fn sign(dataset: &str, root: MerkleRoot, key: &SigningKey) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let mut receipt = Receipt {
+            dataset: dataset.to_string(),
+            leaf_count: root.leaf_count,
+            root,
+            timestamp,
+            key: key.verifying_key().to_bytes(),
+            signature: [0; 64],
+        };
+        receipt.signature = key.sign(&receipt.message()).to_bytes();
+        receipt
+    }
+
+    // The bytes that are signed, every field with its length:
+    // magic | version | dataset length: u64 | dataset | root length: u64 | root as a string | leaf count: u64 | timestamp: u64
+    fn message(&self) -> Vec<u8> {
+        let root = self.root.to_string();
+        let mut bytes = RECEIPT_MAGIC.to_vec();
+        bytes.push(RECEIPT_VERSION);
+        bytes.extend_from_slice(&(self.dataset.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(self.dataset.as_bytes());
+        bytes.extend_from_slice(&(root.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(root.as_bytes());
+        bytes.extend_from_slice(&(self.leaf_count as u64).to_be_bytes());
+        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    /// Check the signature with the key of the receipt, which must be the pinned one if there is one.
+    pub fn verify(&self, pinned_key: Option<&[u8; 32]>) -> Result<(), String> {
+        if pinned_key.is_some_and(|pinned_key| *pinned_key != self.key) {
+            return Err(format!(
+                "Receipt is signed by {}, not by the pinned server key",
+                hex::encode(self.key)
+            ));
+        }
+        let key = VerifyingKey::from_bytes(&self.key)
+            .map_err(|e| format!("Invalid server key: {}", e))?;
+        key.verify(&self.message(), &Signature::from_bytes(&self.signature))
+            .map_err(|_| "Invalid receipt signature".to_string())
+    }
+
+    /// Check that the server has as many files as the client uploaded, with the root the client calculated.
+    pub fn check(&self, expected: &MerkleRoot) -> Result<(), String> {
+        if self.leaf_count != expected.leaf_count || self.root.leaf_count != expected.leaf_count {
+            return Err(format!(
+                "Server has {} files, expected {}",
+                self.leaf_count, expected.leaf_count
+            ));
+        }
+        if self.root != *expected {
+            return Err(format!(
+                "Server calculated the merkle root {}, expected {}",
+                self.root, expected
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Parse a public key given in hex, e.g. to pin it.
+pub fn parse_key(hex_key: &str) -> Result<[u8; 32], String> {
+    let mut key = [0u8; 32];
+    hex::decode_to_slice(hex_key.trim(), &mut key)
+        .map_err(|e| format!("Invalid server key {}: {}", hex_key, e))?;
+    VerifyingKey::from_bytes(&key).map_err(|e| format!("Invalid server key {}: {}", hex_key, e))?;
+    Ok(key)
+}
+
+/// Read the signing key kept in the file, or generate one and keep it there, readable only by its owner.
+pub fn load_or_generate_key<P: AsRef<Path>>(path: P) -> io::Result<SigningKey> {
+    let path = path.as_ref();
+    if path.exists() {
+        let bytes = fs::read(path)?;
+        let secret: [u8; 32] = bytes.try_into().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not an Ed25519 key", path.display()),
+            )
+        })?;
+        return Ok(SigningKey::from_bytes(&secret));
+    }
+    let key = SigningKey::generate(&mut rand::rngs::OsRng);
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    io::Write::write_all(&mut options.open(path)?, &key.to_bytes())?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hasher::*;
+    use crate::receipt::*;
+
+    fn root(leaf_count: usize, hash: [u8; 32]) -> MerkleRoot {
+        MerkleRoot {
+            algorithm: HashAlgorithm::Sha256,
+            mode: TreeMode::Rfc6962,
+            leaf_count,
+            hash,
+        }
+    }
+
+    #[test]
+    fn receipts_must_match_the_root() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let receipt = Receipt::sign("default", root(3, [1; 32]), &key);
+        let json = serde_json::to_string(&receipt).unwrap();
+        assert_eq!(serde_json::from_str::<Receipt>(&json).unwrap(), receipt);
+        assert_eq!(receipt.check(&root(3, [1; 32])), Ok(()));
+        assert!(receipt.check(&root(3, [2; 32])).is_err());
+        assert!(receipt.check(&root(4, [1; 32])).is_err());
+        let miscounted = Receipt {
+            leaf_count: 4,
+            ..receipt.clone()
+        };
+        assert!(miscounted.check(&root(3, [1; 32])).is_err());
+    }
+
+    #[test]
+    fn receipts_are_signed() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let public_key = key.verifying_key().to_bytes();
+        let receipt = Receipt::sign("default", root(3, [1; 32]), &key);
+        assert_eq!(receipt.verify(None), Ok(()));
+        assert_eq!(receipt.verify(Some(&public_key)), Ok(()));
+        assert_eq!(parse_key(&hex::encode(public_key)), Ok(public_key));
+        // the pinned key is the only one accepted
+        let other = SigningKey::from_bytes(&[8; 32]);
+        assert!(receipt
+            .verify(Some(&other.verifying_key().to_bytes()))
+            .is_err());
+        let resigned = Receipt::sign("default", root(3, [1; 32]), &other);
+        assert!(resigned.verify(Some(&public_key)).is_err());
+        // every field is signed
+        let changed = [
+            Receipt {
+                dataset: "other".to_string(),
+                ..receipt.clone()
+            },
+            Receipt {
+                root: root(3, [2; 32]),
+                ..receipt.clone()
+            },
+            Receipt {
+                leaf_count: 4,
+                ..receipt.clone()
+            },
+            Receipt {
+                timestamp: receipt.timestamp + 1,
+                ..receipt.clone()
+            },
+            Receipt {
+                key: public_key.map(|byte| byte ^ 1),
+                ..receipt.clone()
+            },
+        ];
+        for other in changed {
+            assert!(other.verify(None).is_err(), "{:?}", other);
+        }
+    }
+}
@@ -1,58 +1,768 @@
+use crate::chunks::*;
+use crate::dedup::*;
+use crate::entry::*;
+use crate::hasher::*;
 use crate::merkle::*;
-use actix_files;
+use crate::mmr::*;
+use crate::proof::{Proof, ProofEncoding};
+use crate::receipt::*;
+use crate::sorted::*;
+use crate::sparse::*;
+use crate::tree_file::MappedTree;
 use actix_files::NamedFile;
 use actix_multipart::Multipart;
-use actix_web::{get, web, App, HttpResponse, HttpServer, Responder, Result};
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{
+    error, get, post, put, web, App, Either, FromRequest, HttpRequest, HttpResponse, HttpServer,
+    Responder, Result,
+};
+use ed25519_dalek::SigningKey;
+use futures::future::{ready, Ready};
 use futures::{StreamExt, TryStreamExt};
-use serde::Serialize;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
-#[derive(Serialize)]
-struct Message {
-    message: String,
-}
-
-fn compute_proofs_if_needed() -> Result<()> {
-    let proofs_dir = PathBuf::from("proofs");
-    if !proofs_dir.exists() {
-        println!("Computing proofs...");
-        std::fs::create_dir(proofs_dir)?;
-        let files = list_files_in_order("files")?;
-        println!("Files: {:?}", files);
-        let mut hashes: Vec<[u8; 32]> = Vec::with_capacity(files.len());
-        for file in &files {
-            let hash = hash_file_by_path(&file)?;
-            hashes.push(hash);
-        }
-        let merkle_tree = MerkleTree::from_hashes(hashes);
-        println!("Merkle root: {}", hex_hash(merkle_tree.get_merkle_root()));
-        for file in &files {
-            let index = match file
-                .file_name()
-                .map(|s| s.to_str().map(|s| s.parse::<usize>()))
-            {
-                Some(Some(Ok(index))) => index,
-                _ => {
-                    return Err(actix_web::Error::from(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!(
-                            "Invalid filename. Must be an index of the file, but got: {}",
-                            file.display()
-                        ),
-                    )));
-                }
-            };
+// The key the server signs the roots of finalized uploads with, kept in the "server_key" file.
+type ServerKey = web::Data<SigningKey>;
+
+// The mountain range of the uploaded files, persisted in the `mmr` file and extended as files arrive,
+// so proofs never need all the files hashed again.
+type MmrCache = Mutex<Option<MerkleMountainRange>>;
+
+// The tree of the uploaded files persisted in the `tree` file and mapped, to serve proofs from
+// and to append to or update, so the files are only hashed again when there is no tree file.
+type MappedTreeCache = Mutex<Option<MappedTree>>;
+
+// The sparse tree of the named files, built on the first proof by name after they changed.
+type SparseTreeCache = Mutex<Option<SparseMerkleTree>>;
+
+// The tree of the named files sorted by name, built on the first absence proof after they changed.
+type SortedTreeCache = Mutex<Option<SortedMerkleTree>>;
+
+// The dataset of the routes without a dataset, kept in the directory the server runs in,
+// where the server kept its files before there were datasets.
+const DEFAULT_DATASET: &str = "default";
+
+// The directory the named datasets are kept in, each in its own subdirectory.
+const DATASETS_DIR: &str = "datasets";
+
+// A set of files uploaded together, with the trees and the receipts of their roots,
+// all kept in its own directory so uploads to one dataset never touch the files of another.
+// Only the chunk store is shared, chunks are addressed by their content.
+struct Dataset {
+    id: String,
+    dir: PathBuf,
+    mmr: MmrCache,
+    mapped: MappedTreeCache,
+    sparse: SparseTreeCache,
+    sorted: SortedTreeCache,
+}
+
+impl Dataset {
+    fn new(id: &str, dir: PathBuf) -> Self {
+        Dataset {
+            id: id.to_string(),
+            dir,
+            mmr: Mutex::new(None),
+            mapped: Mutex::new(None),
+            sparse: Mutex::new(None),
+            sorted: Mutex::new(None),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+// The datasets of the server by their id.
+type Datasets = web::Data<Mutex<BTreeMap<String, Arc<Dataset>>>>;
+
+// The dataset a request is for: the one in the `/datasets/{dataset}` scope, the default one outside of it.
+struct DatasetRef(Arc<Dataset>);
+
+impl std::ops::Deref for DatasetRef {
+    type Target = Dataset;
+
+    fn deref(&self) -> &Dataset {
+        &self.0
+    }
+}
+
+impl FromRequest for DatasetRef {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let id = req.match_info().get("dataset").unwrap_or(DEFAULT_DATASET);
+        let dataset = req
+            .app_data::<Datasets>()
+            .and_then(|datasets| datasets.lock().unwrap().get(id).cloned());
+        ready(match dataset {
+            Some(dataset) => Ok(DatasetRef(dataset)),
+            None => Err(error::ErrorNotFound(format!("There is no dataset {}", id))),
+        })
+    }
+}
+
+// Dataset ids are directory names: letters, digits, `-`, `_` and `.`, not only dots, at most 64 of them.
+fn check_dataset_id(id: &str) -> std::result::Result<(), String> {
+    if id.is_empty()
+        || id.len() > 64
+        || id.chars().all(|c| c == '.')
+        || !id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(format!("Invalid dataset id: {:?}", id));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct UploadParams {
+    algorithm: Option<String>,
+    mode: Option<String>,
+    // add the files to the current ones instead of replacing them
+    append: Option<bool>,
+    // the original file name, to address the file by name
+    name: Option<String>,
+    // the file is the recipe of its chunks, which are already in the chunk store
+    recipe: Option<bool>,
+}
+
+// The entry of an uploaded or updated file, bound into its leaf if there is a path.
+// The size and the content hash are the server's own.
+#[derive(Deserialize)]
+struct EntryParams {
+    path: Option<String>,
+    permissions: Option<u32>,
+    mtime: Option<i64>,
+    symlink: Option<bool>,
+}
+
+impl EntryParams {
+    // Check the path and that the tree can bind it, before anything is stored.
+    fn check(&self, mode: TreeMode) -> std::result::Result<(), String> {
+        match &self.path {
+            Some(path) => check_path(path).and(check_entry_mode(mode)),
+            None => Ok(()),
+        }
+    }
+}
+
+// The segments of the routes, by name, as the routes of a dataset have its id in their path as well.
+#[derive(Deserialize)]
+struct FilePath {
+    fileindex: usize,
+}
+
+#[derive(Deserialize)]
+struct ChunkPath {
+    fileindex: usize,
+    chunk: usize,
+}
+
+#[derive(Deserialize)]
+struct ChunkKeyPath {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct ConsistencyPath {
+    old_size: usize,
+}
+
+#[derive(Deserialize)]
+struct SizeParams {
+    // the number of files at the time of the root, the current number by default
+    size: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct NameParams {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct MultiproofParams {
+    // comma separated file indices
+    indices: String,
+}
+
+// The first proof encoding the client accepts, binary if it doesn't say or accepts anything.
+fn proof_encoding(accept: Option<web::Header<header::Accept>>) -> ProofEncoding {
+    accept
+        .and_then(|accept| {
+            accept
+                .ranked()
+                .iter()
+                .find_map(|mime| ProofEncoding::from_content_type(mime.essence_str()))
+        })
+        .unwrap_or_default()
+}
+
+fn proof_response(proof: &Proof, encoding: ProofEncoding, root_hash: &[u8; 32]) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(encoding.content_type())
+        .body(proof.encode_as(encoding, root_hash))
+}
+
+// The signed receipt of the root, kept in `receipts/<leaf count>` when the upload with this root was finalized.
+fn read_receipt(dataset: &Dataset, root: &MerkleRoot) -> Result<Option<Receipt>> {
+    let receipt_path = dataset.path("receipts").join(root.leaf_count.to_string());
+    if !receipt_path.exists() {
+        return Ok(None);
+    }
+    let receipt: Receipt = serde_json::from_slice(&std::fs::read(receipt_path)?)?;
+    Ok(Some(receipt).filter(|receipt| receipt.root == *root))
+}
+
+// Attach the signed receipt of the root the response proves something against, if there is one.
+fn with_receipt(
+    dataset: &Dataset,
+    mut response: HttpResponse,
+    root: &MerkleRoot,
+) -> Result<HttpResponse> {
+    if response.status().is_success() {
+        if let Some(receipt) = read_receipt(dataset, root)? {
+            response.headers_mut().insert(
+                header::HeaderName::from_static(RECEIPT_HEADER),
+                header::HeaderValue::from_str(&serde_json::to_string(&receipt)?)
+                    .map_err(|e| invalid_data(e.to_string()))?,
+            );
+        }
+    }
+    Ok(response)
+}
+
+// Drop the receipts after a file changed, the roots they sign are gone.
+fn remove_receipts(dataset: &Dataset) -> Result<()> {
+    let receipts_dir = dataset.path("receipts");
+    if receipts_dir.exists() {
+        std::fs::remove_dir_all(receipts_dir)?;
+    }
+    Ok(())
+}
+
+async fn read_body(mut body: web::Payload) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk?);
+    }
+    Ok(bytes)
+}
+
+// Read the request body, or None as soon as it's longer than limit.
+async fn read_body_up_to(mut body: web::Payload, limit: usize) -> Result<Option<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > limit {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+fn invalid_data(message: String) -> actix_web::Error {
+    actix_web::Error::from(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        message,
+    ))
+}
+
+// The hash algorithm and the tree mode are chosen by the client on upload
+// and kept next to the files as `<algorithm>:<mode>`.
+// Clients that don't send them get the plain SHA256 tree they always had.
+fn read_tree_params(dataset: &Dataset) -> Result<(HashAlgorithm, TreeMode)> {
+    let params_path = dataset.path("tree_params");
+    if !params_path.exists() {
+        return Ok((HashAlgorithm::Sha256, TreeMode::Plain));
+    }
+    let params = std::fs::read_to_string(params_path)?;
+    let Some((algorithm, mode)) = params.trim().split_once(':') else {
+        return Err(invalid_data(format!("Invalid tree params: {}", params)));
+    };
+    Ok((
+        algorithm.parse().map_err(invalid_data)?,
+        mode.parse().map_err(invalid_data)?,
+    ))
+}
+
+// A file is stored whole in `files/<index>`, or as the recipe of its chunks in `recipes/<index>`
+// if it was uploaded deduplicated, with the chunks in the chunk store shared by all the files.
+enum StoredFile {
+    Whole(PathBuf),
+    Chunked(Recipe),
+}
+
+fn chunk_store() -> ChunkStore {
+    ChunkStore::new("chunks")
+}
+
+impl StoredFile {
+    // The file with the given index, if there is one.
+    fn open(dataset: &Dataset, index: usize) -> Result<Option<StoredFile>> {
+        let filepath = dataset.path("files").join(index.to_string());
+        if filepath.exists() {
+            return Ok(Some(StoredFile::Whole(filepath)));
+        }
+        let recipe_path = dataset.path("recipes").join(index.to_string());
+        if recipe_path.exists() {
+            let recipe = std::fs::read_to_string(recipe_path)?
+                .parse()
+                .map_err(invalid_data)?;
+            return Ok(Some(StoredFile::Chunked(recipe)));
+        }
+        Ok(None)
+    }
+
+    fn size(&self) -> std::io::Result<u64> {
+        match self {
+            StoredFile::Whole(filepath) => Ok(std::fs::metadata(filepath)?.len()),
+            StoredFile::Chunked(recipe) => Ok(recipe.size()),
+        }
+    }
+
+    fn reader(&self) -> std::io::Result<Box<dyn Read>> {
+        match self {
+            StoredFile::Whole(filepath) => Ok(Box::new(File::open(filepath)?)),
+            StoredFile::Chunked(recipe) => Ok(Box::new(chunk_store().reader(recipe))),
+        }
+    }
+
+    fn read(&self) -> std::io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.reader()?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // At most `length` bytes from the given offset on.
+    fn read_range(&self, offset: u64, length: u64) -> std::io::Result<Vec<u8>> {
+        match self {
+            StoredFile::Whole(filepath) => {
+                let mut file = File::open(filepath)?;
+                file.seek(SeekFrom::Start(offset))?;
+                let mut bytes = Vec::new();
+                file.take(length).read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+            StoredFile::Chunked(recipe) => chunk_store().read_range(recipe, offset, length),
+        }
+    }
+
+    fn hash(&self, mode: TreeMode, algorithm: &HashAlgorithm) -> std::io::Result<[u8; 32]> {
+        hash_file_reader(algorithm, mode, &mut self.reader()?)
+    }
+}
+
+// The entry of the file with the given index, kept in `entries/<index>` if it was uploaded with a path.
+fn read_entry(dataset: &Dataset, index: usize) -> Result<Option<FileEntry>> {
+    let entry_path = dataset.path("entries").join(index.to_string());
+    if !entry_path.exists() {
+        return Ok(None);
+    }
+    let entry = serde_json::from_slice(&std::fs::read(entry_path)?)
+        .map_err(|e| invalid_data(format!("Invalid entry of file index {}: {}", index, e)))?;
+    Ok(Some(entry))
+}
+
+// The leaf of the file with the given index and content hash: the hash of its entry if it has one.
+fn file_leaf(
+    dataset: &Dataset,
+    index: usize,
+    hash: [u8; 32],
+    algorithm: &HashAlgorithm,
+) -> Result<[u8; 32]> {
+    match read_entry(dataset, index)? {
+        Some(entry) => {
+            entry.check(&hash).map_err(invalid_data)?;
+            Ok(entry.leaf(algorithm))
+        }
+        None => Ok(hash),
+    }
+}
+
+// The entry of an uploaded or updated file if it came with a path, and the leaf of the file.
+fn file_entry(
+    dataset: &Dataset,
+    params: &EntryParams,
+    file: &StoredFile,
+) -> Result<(Option<FileEntry>, [u8; 32])> {
+    let (algorithm, mode) = read_tree_params(dataset)?;
+    let hash = file.hash(mode, &algorithm)?;
+    let Some(path) = &params.path else {
+        return Ok((None, hash));
+    };
+    let entry = FileEntry {
+        path: path.clone(),
+        size: file.size()?,
+        permissions: params.permissions,
+        mtime: params.mtime,
+        hash,
+        symlink: params.symlink.unwrap_or(false),
+    };
+    let leaf = entry.leaf(&algorithm);
+    Ok((Some(entry), leaf))
+}
+
+// Keep the entry of the file with the given index, or drop the old one if there is none.
+fn write_entry(dataset: &Dataset, index: usize, entry: Option<&FileEntry>) -> Result<()> {
+    let entry_path = dataset.path("entries").join(index.to_string());
+    match entry {
+        Some(entry) => {
+            std::fs::create_dir_all(dataset.path("entries"))?;
+            std::fs::write(entry_path, serde_json::to_vec(entry)?)?;
+        }
+        None if entry_path.exists() => std::fs::remove_file(entry_path)?,
+        None => {}
+    }
+    Ok(())
+}
 
-            let proof = merkle_tree.make_merkle_proof(index);
-            let proof_file_path = PathBuf::from("proofs").join(index.to_string());
-            let mut proof_file = File::create(proof_file_path)?;
-            // Convert Vec<[u8; 32]> to Vec<u8>
-            let flattened: Vec<u8> = proof.into_iter().flatten().collect();
-            proof_file.write_all(&flattened)?;
+// Keep the entry of an uploaded file if it came with a path, drop the old one otherwise,
+// and return the leaf of the file.
+fn store_entry(
+    dataset: &Dataset,
+    index: usize,
+    params: &EntryParams,
+    file: &StoredFile,
+) -> Result<[u8; 32]> {
+    let (entry, leaf) = file_entry(dataset, params, file)?;
+    write_entry(dataset, index, entry.as_ref())?;
+    Ok(leaf)
+}
+
+// The indices of the files in a directory of the dataset, where files are named by their index.
+fn list_indices(dataset: &Dataset, dir: &str) -> Result<Vec<usize>> {
+    let dir = dataset.path(dir);
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut indices = Vec::new();
+    for file in list_files_in_order(dir)? {
+        // an update that didn't complete, the file it was for is unchanged
+        if file.extension().is_some_and(|extension| extension == "tmp") {
+            continue;
+        }
+        match file
+            .file_name()
+            .map(|s| s.to_str().map(|s| s.parse::<usize>()))
+        {
+            Some(Some(Ok(index))) => indices.push(index),
+            _ => {
+                return Err(invalid_data(format!(
+                    "Invalid filename. Must be an index of the file, but got: {}",
+                    file.display()
+                )));
+            }
         }
     }
+    Ok(indices)
+}
+
+// The indices of the stored files, whole or chunked, which must be all the indices up to their number.
+fn file_indices(dataset: &Dataset) -> Result<Vec<usize>> {
+    let mut indices = list_indices(dataset, "files")?;
+    indices.extend(list_indices(dataset, "recipes")?);
+    // the leaves are in the index order, listing by name would put "10" before "2"
+    indices.sort();
+    if let Some((position, index)) = indices
+        .iter()
+        .enumerate()
+        .find(|(position, index)| position != *index)
+    {
+        return Err(invalid_data(format!(
+            "Missing file index {}, found {} instead",
+            position, index
+        )));
+    }
+    Ok(indices)
+}
+
+fn build_tree(dataset: &Dataset) -> Result<MerkleTree> {
+    let (algorithm, mode) = read_tree_params(dataset)?;
+    let indices = file_indices(dataset)?;
+    let mut files = Vec::with_capacity(indices.len());
+    for index in indices {
+        files.push(StoredFile::open(dataset, index)?.unwrap());
+    }
+    println!("Files: {}", files.len());
+    let mut hashes = hash_readers(&files, mode, &algorithm, StoredFile::reader)?;
+    for (index, hash) in hashes.iter_mut().enumerate() {
+        *hash = file_leaf(dataset, index, *hash, &algorithm)?;
+    }
+    let merkle_tree = MerkleTree::from_hashes(hashes, mode, algorithm);
+    println!("Merkle root: {}", merkle_tree.root());
+    Ok(merkle_tree)
+}
+
+// The original names of the uploaded files by their index, kept in `names/<index>`.
+fn read_names(dataset: &Dataset) -> Result<Vec<(usize, String)>> {
+    let names_dir = dataset.path("names");
+    if !names_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut names = Vec::new();
+    for file in list_files_in_order(names_dir)? {
+        let index = file
+            .file_name()
+            .and_then(|s| s.to_str())
+            .and_then(|s| s.parse::<usize>().ok())
+            .ok_or_else(|| invalid_data(format!("Invalid name file: {}", file.display())))?;
+        names.push((index, std::fs::read_to_string(file)?));
+    }
+    Ok(names)
+}
+
+// The names of the files that were uploaded with a name, together with the file hashes.
+fn named_leaves(dataset: &Dataset, tree: &MappedTree) -> Result<Vec<(String, [u8; 32])>> {
+    let leaf_count = tree.root().leaf_count;
+    let mut leaves = Vec::new();
+    for (index, name) in read_names(dataset)? {
+        let Some(hash) = tree.leaf(index) else {
+            return Err(invalid_data(format!(
+                "File {} has index {}, but there are only {} files",
+                name, index, leaf_count
+            )));
+        };
+        leaves.push((name, hash));
+    }
+    Ok(leaves)
+}
+
+// The sparse tree of the named files, keyed by the name.
+fn build_sparse_tree(dataset: &Dataset, tree: &MappedTree) -> Result<SparseMerkleTree> {
+    let root = tree.root();
+    let mut sparse_tree = SparseMerkleTree::new(root.mode, root.algorithm);
+    for (name, hash) in named_leaves(dataset, tree)? {
+        if sparse_tree
+            .insert(name_key(&root.algorithm, &name), hash)
+            .is_some()
+        {
+            return Err(invalid_data(format!("Duplicate file name: {}", name)));
+        }
+    }
+    Ok(sparse_tree)
+}
+
+// The sorted tree of the named files, ordered by the key of the name.
+fn build_sorted_tree(dataset: &Dataset, tree: &MappedTree) -> Result<SortedMerkleTree> {
+    let root = tree.root();
+    let entries = named_leaves(dataset, tree)?
+        .into_iter()
+        .map(|(name, hash)| (name_key(&root.algorithm, &name), hash))
+        .collect();
+    SortedMerkleTree::from_entries(entries, root.mode, root.algorithm).map_err(invalid_data)
+}
+
+// Load the persisted mountain range, or build it from the files,
+// e.g. when they were uploaded before the server kept one.
+fn load_mmr(dataset: &Dataset) -> Result<MerkleMountainRange> {
+    let (algorithm, mode) = read_tree_params(dataset)?;
+    let mmr_path = dataset.path("mmr");
+    if mmr_path.exists() {
+        return Ok(MerkleMountainRange::load(mmr_path, mode, algorithm)?);
+    }
+    println!("Building merkle mountain range...");
+    build_mmr(dataset, build_tree(dataset)?.leaves())
+}
+
+// Build the mountain range of the given leaves and persist it.
+fn build_mmr(dataset: &Dataset, leaves: &[[u8; 32]]) -> Result<MerkleMountainRange> {
+    let (algorithm, mode) = read_tree_params(dataset)?;
+    let mut mmr = MerkleMountainRange::new(mode, algorithm);
+    for leaf in leaves {
+        mmr.append(*leaf);
+    }
+    mmr.save(dataset.path("mmr"))?;
+    Ok(mmr)
+}
+
+// Load the persisted mountain range when the server starts, after checking it against the files.
+// A range that doesn't match them is built again from the files.
+fn load_mmr_file(dataset: &Dataset) -> Result<()> {
+    let mmr_path = dataset.path("mmr");
+    if !mmr_path.exists() {
+        return Ok(());
+    }
+    println!("Checking merkle mountain range...");
+    let (algorithm, mode) = read_tree_params(dataset)?;
+    let leaves = with_mapped_tree(dataset, |tree| tree.leaves())?;
+    let mmr = match MerkleMountainRange::load(&mmr_path, mode, algorithm) {
+        Ok(mmr) if mmr.matches(&leaves) => mmr,
+        result => {
+            match result {
+                Ok(_) => println!("Merkle mountain range doesn't match the files"),
+                Err(e) => println!("Invalid merkle mountain range: {}", e),
+            }
+            println!("Building merkle mountain range...");
+            build_mmr(dataset, &leaves)?
+        }
+    };
+    *dataset.mmr.lock().unwrap() = Some(mmr);
+    Ok(())
+}
+
+fn with_mmr<T>(dataset: &Dataset, f: impl FnOnce(&MerkleMountainRange) -> T) -> Result<T> {
+    let mut mmr = dataset.mmr.lock().unwrap();
+    if mmr.is_none() {
+        *mmr = Some(load_mmr(dataset)?);
+    }
+    Ok(f(mmr.as_ref().unwrap()))
+}
+
+// Add the leaf of an uploaded file to the mountain range.
+// Files normally arrive in the index order, anything else drops the range to build it again when needed.
+fn add_to_mmr(dataset: &Dataset, index: usize, leaf: [u8; 32], new_upload: bool) -> Result<()> {
+    let mmr_path = dataset.path("mmr");
+    let mut mmr = dataset.mmr.lock().unwrap();
+    if new_upload && index == 0 {
+        let (algorithm, mode) = read_tree_params(dataset)?;
+        if mmr_path.exists() {
+            std::fs::remove_file(&mmr_path)?;
+        }
+        *mmr = Some(MerkleMountainRange::new(mode, algorithm));
+    } else if mmr.is_none() {
+        // built from the files, which already include this one
+        *mmr = Some(load_mmr(dataset)?);
+    }
+    let range = mmr.as_mut().unwrap();
+    if index == range.leaf_count() {
+        range.append_persisted(&mmr_path, leaf)?;
+    } else if index > range.leaf_count() {
+        *mmr = None;
+        if mmr_path.exists() {
+            std::fs::remove_file(&mmr_path)?;
+        }
+    }
+    Ok(())
+}
+
+// Run f with the mapped tree file of the uploaded files, writing it from the files
+// if there is none, e.g. on the first proof request after a new upload.
+fn with_mapped_tree<T>(dataset: &Dataset, f: impl FnOnce(&mut MappedTree) -> T) -> Result<T> {
+    let mut mapped = dataset.mapped.lock().unwrap();
+    if mapped.is_none() {
+        let tree_path = dataset.path("tree");
+        if !tree_path.exists() {
+            println!("Writing tree file...");
+            MappedTree::write(&tree_path, &build_tree(dataset)?)?;
+        }
+        *mapped = Some(MappedTree::open(&tree_path)?);
+    }
+    Ok(f(mapped.as_mut().unwrap()))
+}
+
+// Map the tree file when the server starts, after checking it against the files without hashing them:
+// it must be a tree of as many leaves as there are files, with the algorithm and mode of the upload,
+// every node the hash of its children. A tree file that doesn't pass is written again from the files.
+fn load_tree_file(dataset: &Dataset) -> Result<()> {
+    let tree_path = dataset.path("tree");
+    let proofs_dir = dataset.path("proofs");
+    let had_proofs = proofs_dir.exists();
+    if !tree_path.exists() && !had_proofs {
+        return Ok(());
+    }
+    println!("Checking tree file...");
+    let (algorithm, mode) = read_tree_params(dataset)?;
+    let file_count = file_indices(dataset)?.len();
+    let mapped_tree = match MappedTree::open(&tree_path) {
+        Ok(mapped_tree)
+            if mapped_tree.root().leaf_count == file_count
+                && mapped_tree.root().algorithm == algorithm
+                && mapped_tree.root().mode == mode
+                && mapped_tree.verify_nodes() =>
+        {
+            println!("Merkle root: {}", mapped_tree.root());
+            mapped_tree
+        }
+        result => {
+            match result {
+                Ok(_) => println!("Tree file doesn't match the files"),
+                Err(e) => println!("Invalid tree file: {}", e),
+            }
+            println!("Writing tree file...");
+            MappedTree::write(&tree_path, &build_tree(dataset)?)?;
+            MappedTree::open(&tree_path)?
+        }
+    };
+    // the proof files of older versions are replaced by the tree file, once it's written
+    if had_proofs {
+        std::fs::remove_dir_all(proofs_dir)?;
+    }
+    *dataset.mapped.lock().unwrap() = Some(mapped_tree);
+    Ok(())
+}
+
+// The tree of the chunks of the file with the given index, persisted in `chunk_trees/<index>`
+// on the first chunk proof request, or `None` if there is no such file.
+fn load_chunk_tree(dataset: &Dataset, index: usize) -> Result<Option<MappedTree>> {
+    let Some(file) = StoredFile::open(dataset, index)? else {
+        return Ok(None);
+    };
+    let tree_path = dataset.path("chunk_trees").join(index.to_string());
+    if !tree_path.exists() {
+        println!("Writing chunk tree {}...", index);
+        let (algorithm, mode) = read_tree_params(dataset)?;
+        let tree = chunk_tree(algorithm, mode, &mut file.reader()?)?;
+        std::fs::create_dir_all(dataset.path("chunk_trees"))?;
+        MappedTree::write(&tree_path, &tree)?;
+    }
+    Ok(Some(MappedTree::open(tree_path)?))
+}
+
+// Drop the chunk tree of a file that changed.
+fn remove_chunk_tree(dataset: &Dataset, index: usize) -> Result<()> {
+    let tree_path = dataset.path("chunk_trees").join(index.to_string());
+    if tree_path.exists() {
+        std::fs::remove_file(tree_path)?;
+    }
+    Ok(())
+}
+
+// Drop the tree file after the files were replaced, it's written again on the next proof request.
+fn remove_tree_file(dataset: &Dataset) -> Result<()> {
+    *dataset.mapped.lock().unwrap() = None;
+    let tree_path = dataset.path("tree");
+    if tree_path.exists() {
+        std::fs::remove_file(tree_path)?;
+    }
+    Ok(())
+}
+
+// Drop the trees of the names after a file or its name changed, they're built again on the next request.
+fn remove_name_trees(dataset: &Dataset) {
+    *dataset.sparse.lock().unwrap() = None;
+    *dataset.sorted.lock().unwrap() = None;
+}
+
+// The first file of a new upload replaces all the files of the dataset, with everything kept about them,
+// whether or not the last upload was finalized.
+fn start_upload(dataset: &Dataset) -> Result<()> {
+    remove_tree_file(dataset)?;
+    *dataset.mmr.lock().unwrap() = None;
+    remove_name_trees(dataset);
+    let mmr_path = dataset.path("mmr");
+    if mmr_path.exists() {
+        std::fs::remove_file(mmr_path)?;
+    }
+    // the chunks are kept, a new upload of similar files only sends the chunks that changed
+    for dir in ["files", "recipes", "chunk_trees", "names", "entries"] {
+        let dir = dataset.path(dir);
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)?;
+        }
+    }
+    remove_receipts(dataset)?;
+    std::fs::create_dir(dataset.path("files"))?;
     Ok(())
 }
 
@@ -60,18 +770,62 @@ async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello, Ralph Merkle!".to_string())
 }
 
-async fn upload_file(mut payload: Multipart) -> Result<HttpResponse> {
-    // remove proofs dir if it exists
-    let files_dir = PathBuf::from("files");
-    let proofs_dir = PathBuf::from("proofs");
-    if proofs_dir.exists() {
-        std::fs::remove_dir_all(proofs_dir)?;
-        std::fs::remove_dir_all(&files_dir)?;
+// The hash algorithms the server can build trees with, one per line.
+async fn algorithms() -> impl Responder {
+    let algorithms: Vec<String> = HashAlgorithm::ALL.iter().map(|a| a.to_string()).collect();
+    HttpResponse::Ok().body(algorithms.join("\n"))
+}
+
+async fn upload_file(
+    params: web::Query<UploadParams>,
+    entry_params: web::Query<EntryParams>,
+    dataset: DatasetRef,
+    mut payload: Multipart,
+) -> Result<HttpResponse> {
+    let algorithm = match &params.algorithm {
+        Some(algorithm) => match algorithm.parse::<HashAlgorithm>() {
+            Ok(algorithm) => algorithm,
+            Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+        },
+        None => HashAlgorithm::Sha256,
+    };
+    let mode = match &params.mode {
+        Some(mode) => match mode.parse::<TreeMode>() {
+            Ok(mode) => mode,
+            Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+        },
+        None => TreeMode::Plain,
+    };
+    let append = params.append.unwrap_or(false);
+    // appended files go into the tree of the files they are appended to
+    let tree_mode = if append {
+        read_tree_params(&dataset)?.1
+    } else {
+        mode
+    };
+    if let Err(e) = entry_params.check(tree_mode) {
+        return Ok(HttpResponse::BadRequest().body(e));
     }
-    // create files directory if it doesn't exist
-    if !files_dir.exists() {
-        std::fs::create_dir(files_dir)?;
+    let files_dir = dataset.path("files");
+    let names_dir = dataset.path("names");
+    if append {
+        // the files and the tree file are kept, the new leaves are appended to it
+        if !files_dir.exists() {
+            return Ok(HttpResponse::NotFound().body("There are no files to append to"));
+        }
+    } else {
+        // the tree file doesn't have the files of this upload, whatever was asked for in between
+        remove_tree_file(&dataset)?;
+        // create files directory if it doesn't exist
+        if !files_dir.exists() {
+            std::fs::create_dir(&files_dir)?;
+        }
+        std::fs::write(
+            dataset.path("tree_params"),
+            format!("{}:{}", algorithm, mode),
+        )?;
     }
+    let mut appended = Vec::new();
     // iterate over multipart stream
     while let Ok(Some(mut field)) = payload.try_next().await {
         let content_disposition = field.content_disposition();
@@ -91,48 +845,675 @@ async fn upload_file(mut payload: Multipart) -> Result<HttpResponse> {
                 )));
             }
         };
-        let filepath = PathBuf::from("files").join(index.to_string());
+        if !append && index == 0 {
+            start_upload(&dataset)?;
+        }
+        let filepath = dataset.path("files").join(index.to_string());
+        let recipe_path = dataset.path("recipes").join(index.to_string());
         println!("File index {}, path {}", index, filepath.display());
+        if append && StoredFile::open(&dataset, index)?.is_some() {
+            return Ok(HttpResponse::Conflict().body(format!(
+                "File index {} already exists, files can only be appended",
+                index
+            )));
+        }
 
-        // File::create is blocking operation, use threadpool
-        let mut f = std::fs::File::create(filepath)?;
-        // Field in turn is stream of *Bytes* object
-        while let Some(chunk) = field.next().await {
-            let data = chunk?;
-            f.write_all(&data)?;
+        if params.recipe.unwrap_or(false) {
+            let mut bytes = Vec::new();
+            while let Some(chunk) = field.next().await {
+                bytes.extend_from_slice(&chunk?);
+            }
+            let recipe = match String::from_utf8_lossy(&bytes).parse::<Recipe>() {
+                Ok(recipe) => recipe,
+                Err(e) => return Ok(HttpResponse::BadRequest().body(e)),
+            };
+            if let Err(e) = chunk_store().check(&recipe) {
+                return Ok(HttpResponse::BadRequest().body(e));
+            }
+            std::fs::create_dir_all(dataset.path("recipes"))?;
+            std::fs::write(&recipe_path, recipe.to_string())?;
+            if filepath.exists() {
+                std::fs::remove_file(&filepath)?;
+            }
+        } else {
+            // File::create is blocking operation, use threadpool
+            let mut f = std::fs::File::create(&filepath)?;
+            // Field in turn is stream of *Bytes* object
+            while let Some(chunk) = field.next().await {
+                let data = chunk?;
+                f.write_all(&data)?;
+            }
+            f.sync_all()?;
+            if recipe_path.exists() {
+                std::fs::remove_file(&recipe_path)?;
+            }
+        }
+        remove_chunk_tree(&dataset, index)?;
+        if let Some(name) = &params.name {
+            std::fs::create_dir_all(&names_dir)?;
+            std::fs::write(names_dir.join(index.to_string()), name)?;
+        }
+        let file = StoredFile::open(&dataset, index)?.unwrap();
+        let leaf = store_entry(&dataset, index, &entry_params, &file)?;
+        remove_name_trees(&dataset);
+        add_to_mmr(&dataset, index, leaf, !append)?;
+        if append {
+            appended.push((index, leaf));
         }
-        f.sync_all()?;
+    }
+    if !appended.is_empty() {
+        let tree_path = dataset.path("tree");
+        with_mapped_tree(&dataset, |tree| -> Result<()> {
+            let mut leaves = Vec::new();
+            for (index, leaf) in appended {
+                let leaf_count = tree.root().leaf_count + leaves.len();
+                // a tree file written from the files already has this one
+                if index < leaf_count {
+                    continue;
+                }
+                if index != leaf_count {
+                    return Err(invalid_data(format!(
+                        "Appended file index {} leaves a gap after {} files",
+                        index, leaf_count
+                    )));
+                }
+                leaves.push(leaf);
+            }
+            *tree = tree.append(&tree_path, &leaves)?;
+            println!("Merkle root: {}", tree.root());
+            Ok(())
+        })??;
     }
     Ok(HttpResponse::Ok().finish())
 }
 
+// Whole files are sent as they are, deduplicated ones are streamed chunk by chunk.
 #[get("/files/{fileindex}")]
-async fn download_file(path: web::Path<String>) -> Result<NamedFile> {
-    compute_proofs_if_needed()?;
-    let filename = PathBuf::from("files").join(path.into_inner());
-    println!("Downloading file {}", filename.display());
-    // TODO: ensure that it's impossible to download files outside of the current directory
-    let named_file = NamedFile::open(&filename)?;
-    Ok(named_file)
+async fn download_file(
+    dataset: DatasetRef,
+    path: web::Path<FilePath>,
+) -> Result<Either<NamedFile, HttpResponse>> {
+    let index = path.fileindex;
+    println!("Downloading file {}", index);
+    match StoredFile::open(&dataset, index)? {
+        Some(StoredFile::Whole(filepath)) => Ok(Either::Left(NamedFile::open(filepath)?)),
+        Some(StoredFile::Chunked(recipe)) => {
+            let store = chunk_store();
+            let chunks = recipe
+                .chunks
+                .into_iter()
+                .map(move |(key, _)| store.get(&key).map(web::Bytes::from));
+            Ok(Either::Right(
+                HttpResponse::Ok().streaming(futures::stream::iter(chunks)),
+            ))
+        }
+        None => Ok(Either::Right(
+            HttpResponse::NotFound().body(format!("There is no file index {}", index)),
+        )),
+    }
 }
 
+// The keys of the chunks the chunk store doesn't have, out of the ones in the request body, one per line.
+#[post("/chunks/missing")]
+async fn missing_chunks(body: web::Payload) -> Result<HttpResponse> {
+    let keys = String::from_utf8_lossy(&read_body(body).await?).into_owned();
+    let store = chunk_store();
+    let mut missing = Vec::new();
+    for hex_key in keys.lines() {
+        let mut key = [0u8; 32];
+        if hex::decode_to_slice(hex_key, &mut key).is_err() {
+            return Ok(HttpResponse::BadRequest().body(format!("Invalid chunk key: {}", hex_key)));
+        }
+        if !store.contains(&key) {
+            missing.push(hex_key);
+        }
+    }
+    Ok(HttpResponse::Ok().body(missing.join("\n")))
+}
+
+// Store the chunk in the request body under its key, which must be its SHA256.
+#[put("/chunks/{key}")]
+async fn upload_chunk(path: web::Path<ChunkKeyPath>, body: web::Payload) -> Result<HttpResponse> {
+    let hex_key = path.into_inner().key;
+    let mut key = [0u8; 32];
+    if hex::decode_to_slice(&hex_key, &mut key).is_err() {
+        return Ok(HttpResponse::BadRequest().body(format!("Invalid chunk key: {}", hex_key)));
+    }
+    // no content-defined chunk is larger
+    let Some(chunk) = read_body_up_to(body, MAX_CHUNK_SIZE as usize).await? else {
+        return Ok(HttpResponse::PayloadTooLarge()
+            .body(format!("A chunk has at most {} bytes", MAX_CHUNK_SIZE)));
+    };
+    match chunk_store().put(&key, &chunk) {
+        Ok(()) => Ok(HttpResponse::Ok().finish()),
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+            Ok(HttpResponse::BadRequest().body(e.to_string()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+// The index of the file uploaded with the given name.
+fn named_index(dataset: &Dataset, name: &str) -> Result<Option<usize>> {
+    Ok(read_names(dataset)?
+        .into_iter()
+        .find(|(_, file_name)| file_name == name)
+        .map(|(index, _)| index))
+}
+
+// The entry of a file as JSON, if it was uploaded with a path.
+fn entry_response(dataset: &Dataset, index: usize) -> Result<HttpResponse> {
+    match read_entry(dataset, index)? {
+        Some(entry) => Ok(HttpResponse::Ok().json(entry)),
+        None => Ok(HttpResponse::NotFound().body(format!("File index {} has no entry", index))),
+    }
+}
+
+#[get("/files/{fileindex}/entry")]
+async fn download_entry(dataset: DatasetRef, path: web::Path<FilePath>) -> Result<HttpResponse> {
+    entry_response(&dataset, path.fileindex)
+}
+
+#[get("/names/entry")]
+async fn download_named_entry(
+    dataset: DatasetRef,
+    params: web::Query<NameParams>,
+) -> Result<HttpResponse> {
+    match named_index(&dataset, &params.name)? {
+        Some(index) => entry_response(&dataset, index),
+        None => Ok(HttpResponse::NotFound().body(format!("There is no file {}", params.name))),
+    }
+}
+
+#[get("/names/file")]
+async fn download_named_file(
+    dataset: DatasetRef,
+    params: web::Query<NameParams>,
+) -> Result<HttpResponse> {
+    println!("Downloading file {}", params.name);
+    let Some(index) = named_index(&dataset, &params.name)? else {
+        return Ok(HttpResponse::NotFound().body(format!("There is no file {}", params.name)));
+    };
+    let Some(file) = StoredFile::open(&dataset, index)? else {
+        return Ok(HttpResponse::NotFound().body(format!("There is no file {}", params.name)));
+    };
+    Ok(HttpResponse::Ok().body(file.read()?))
+}
+
+// The chunk with the given index of the file with the given index, see `CHUNK_SIZE`.
+#[get("/files/{fileindex}/chunks/{chunk}")]
+async fn download_chunk(dataset: DatasetRef, path: web::Path<ChunkPath>) -> Result<HttpResponse> {
+    let (index, chunk) = (path.fileindex, path.chunk);
+    println!("Downloading chunk {} of file {}", chunk, index);
+    let Some(file) = StoredFile::open(&dataset, index)? else {
+        return Ok(HttpResponse::NotFound().body(format!("There is no file index {}", index)));
+    };
+    let chunk_count = chunk_count(file.size()?);
+    if chunk >= chunk_count {
+        return Ok(HttpResponse::NotFound().body(format!(
+            "Chunk {} is out of range, file index {} has {} chunks",
+            chunk, index, chunk_count
+        )));
+    }
+    let bytes = file.read_range((chunk * CHUNK_SIZE) as u64, CHUNK_SIZE as u64)?;
+    Ok(HttpResponse::Ok().body(bytes))
+}
+
+// Merkle proof of a chunk against the root of the tree of the chunks of the file,
+// which is the leaf of the file.
+#[get("/files/{fileindex}/chunks/{chunk}/proof")]
+async fn download_chunk_proof(
+    dataset: DatasetRef,
+    path: web::Path<ChunkPath>,
+    accept: Option<web::Header<header::Accept>>,
+) -> Result<HttpResponse> {
+    let (index, chunk) = (path.fileindex, path.chunk);
+    println!("Downloading proof for chunk {} of file {}", chunk, index);
+    let Some(tree) = load_chunk_tree(&dataset, index)? else {
+        return Ok(HttpResponse::NotFound().body(format!("There is no file index {}", index)));
+    };
+    let root = tree.root();
+    match tree.make_proof(chunk) {
+        Some(siblings) => {
+            let proof = Proof {
+                algorithm: root.algorithm,
+                mode: root.mode,
+                leaf_index: chunk,
+                leaf_count: root.leaf_count,
+                siblings,
+            };
+            Ok(proof_response(&proof, proof_encoding(accept), &root.hash))
+        }
+        None => Ok(HttpResponse::NotFound().body(format!(
+            "Chunk {} is out of range, file index {} has {} chunks",
+            chunk, index, root.leaf_count
+        ))),
+    }
+}
+
+// Sparse merkle proof for the file with the given name.
+// If there is no such file, it proves that.
+#[get("/names/proof")]
+async fn download_named_proof(
+    params: web::Query<NameParams>,
+    dataset: DatasetRef,
+) -> Result<HttpResponse> {
+    println!("Downloading proof for file {}", params.name);
+    let proof = with_mapped_tree(&dataset, |tree| -> Result<SparseProof> {
+        let mut sparse_tree = dataset.sparse.lock().unwrap();
+        if sparse_tree.is_none() {
+            *sparse_tree = Some(build_sparse_tree(&dataset, tree)?);
+        }
+        let key = name_key(&tree.root().algorithm, &params.name);
+        Ok(sparse_tree.as_ref().unwrap().make_proof(&key))
+    })??;
+    Ok(HttpResponse::Ok().body(proof.to_bytes()))
+}
+
+// Proof that there is no file with the given name, made of the files with the names next to it.
+#[get("/names/absence")]
+async fn download_absence_proof(
+    params: web::Query<NameParams>,
+    dataset: DatasetRef,
+) -> Result<HttpResponse> {
+    println!("Downloading absence proof for file {}", params.name);
+    let proof = with_mapped_tree(&dataset, |tree| -> Result<Option<AbsenceProof>> {
+        let mut sorted_tree = dataset.sorted.lock().unwrap();
+        if sorted_tree.is_none() {
+            *sorted_tree = Some(build_sorted_tree(&dataset, tree)?);
+        }
+        let key = name_key(&tree.root().algorithm, &params.name);
+        Ok(sorted_tree.as_ref().unwrap().make_absence_proof(&key))
+    })??;
+    match proof {
+        Some(proof) => Ok(HttpResponse::Ok().body(proof.to_string())),
+        None => Ok(HttpResponse::Conflict().body(format!("File {} exists", params.name))),
+    }
+}
+
+// Replace the file with the given index with the request body, with the new entry of the file if there is a path.
+// Responds with the new merkle root, the hash of the old file and the merkle proof of the file,
+// one per line, which together prove that only this file changed.
+#[put("/files/{fileindex}")]
+async fn update_file(
+    path: web::Path<FilePath>,
+    entry_params: web::Query<EntryParams>,
+    dataset: DatasetRef,
+    mut body: web::Payload,
+) -> Result<HttpResponse> {
+    let index = path.fileindex;
+    if let Err(e) = entry_params.check(read_tree_params(&dataset)?.1) {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    let filepath = dataset.path("files").join(index.to_string());
+    if StoredFile::open(&dataset, index)?.is_none() {
+        return Ok(HttpResponse::NotFound().body(format!(
+            "File index {} does not exist, only existing files can be updated",
+            index
+        )));
+    }
+    println!("Updating file {}", filepath.display());
+    // the tree file must be written from the old file, to know its hash
+    with_mapped_tree(&dataset, |_| ())?;
+    // the new content replaces the old file only once all of it arrived and its entry is made,
+    // so a failed update leaves the file as it was
+    let tmp_path = filepath.with_extension("tmp");
+    let received = async {
+        let mut f = std::fs::File::create(&tmp_path)?;
+        while let Some(chunk) = body.next().await {
+            f.write_all(&chunk?)?;
+        }
+        f.sync_all()?;
+        file_entry(
+            &dataset,
+            &entry_params,
+            &StoredFile::Whole(tmp_path.clone()),
+        )
+    }
+    .await;
+    let (entry, leaf) = match received {
+        Ok(received) => received,
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+    };
+    let tree_path = dataset.path("tree");
+    let updated = with_mapped_tree(&dataset, |tree| -> Result<String> {
+        let old_hash = tree
+            .leaf(index)
+            .ok_or_else(|| invalid_data(format!("File index {} is not in the tree file", index)))?;
+        std::fs::rename(&tmp_path, &filepath)?;
+        // the file is stored whole now
+        let recipe_path = dataset.path("recipes").join(index.to_string());
+        if recipe_path.exists() {
+            std::fs::remove_file(recipe_path)?;
+        }
+        write_entry(&dataset, index, entry.as_ref())?;
+        *tree = tree.update_leaf(&tree_path, index, leaf)?;
+        let mut lines = vec![tree.root().to_string(), hex_hash(&old_hash)];
+        lines.extend(tree.make_proof(index).unwrap().iter().map(hex_hash));
+        println!("Merkle root: {}", tree.root());
+        Ok(lines.join("\n"))
+    })?;
+    let response = match updated {
+        Ok(response) => response,
+        Err(e) => {
+            // the file may have changed without its leaf, the tree file is written again from the files
+            remove_tree_file(&dataset)?;
+            return Err(e);
+        }
+    };
+    remove_chunk_tree(&dataset, index)?;
+    remove_name_trees(&dataset);
+    // the mountain range is append-only, it's built again with the new file
+    *dataset.mmr.lock().unwrap() = None;
+    let mmr_path = dataset.path("mmr");
+    if mmr_path.exists() {
+        std::fs::remove_file(mmr_path)?;
+    }
+    remove_receipts(&dataset)?;
+    Ok(HttpResponse::Ok().body(response))
+}
+
+// Proofs are read from the mapped tree file and sent in the binary format,
+// unless the client asks for another encoding.
+// Merkle proof of the file with the given index, served from the tree file.
+// A proof against the root of fewer files than there are now is served from the mountain range.
 #[get("/proofs/{fileindex}")]
-async fn download_proof(path: web::Path<String>) -> Result<NamedFile> {
-    compute_proofs_if_needed()?;
-    let file_path = PathBuf::from("proofs").join(path.into_inner());
-    println!("Downloading proof {}", file_path.display());
-    // TODO: ensure that it's impossible to download files outside of the current directory
-    let named_file = NamedFile::open(&file_path)?;
-    Ok(named_file)
+async fn download_proof(
+    path: web::Path<FilePath>,
+    params: web::Query<SizeParams>,
+    accept: Option<web::Header<header::Accept>>,
+    dataset: DatasetRef,
+) -> Result<HttpResponse> {
+    let index = path.fileindex;
+    println!("Downloading proof {}", index);
+    let encoding = proof_encoding(accept);
+    let (response, root) = with_mapped_tree(&dataset, |tree| {
+        let root = tree.root();
+        if params.size.is_some_and(|size| size != root.leaf_count) {
+            return (None, root);
+        }
+        let response = match tree.make_proof(index) {
+            Some(siblings) => {
+                let proof = Proof {
+                    algorithm: root.algorithm,
+                    mode: root.mode,
+                    leaf_index: index,
+                    leaf_count: root.leaf_count,
+                    siblings,
+                };
+                proof_response(&proof, encoding, &root.hash)
+            }
+            None => HttpResponse::NotFound().body(format!(
+                "File index {} is out of range, there are {} files",
+                index, root.leaf_count
+            )),
+        };
+        (Some(response), root)
+    })?;
+    match response {
+        Some(response) => with_receipt(&dataset, response, &root),
+        None => mmr_proof_response(&dataset, index, params.size, encoding),
+    }
+}
+
+// Merkle proof of the file with the given index against the root of the first `size` files.
+// It's served from the mountain range, the root of which is the same as the one of the tree.
+#[get("/mmr/proofs/{fileindex}")]
+async fn download_mmr_proof(
+    path: web::Path<FilePath>,
+    params: web::Query<SizeParams>,
+    accept: Option<web::Header<header::Accept>>,
+    dataset: DatasetRef,
+) -> Result<HttpResponse> {
+    let index = path.fileindex;
+    println!("Downloading proof {} from merkle mountain range", index);
+    mmr_proof_response(&dataset, index, params.size, proof_encoding(accept))
+}
+
+fn mmr_proof_response(
+    dataset: &Dataset,
+    index: usize,
+    size: Option<usize>,
+    encoding: ProofEncoding,
+) -> Result<HttpResponse> {
+    let (response, root) = with_mmr(dataset, |mmr| {
+        let size = size.unwrap_or(mmr.leaf_count());
+        match (mmr.make_proof(index, size), mmr.root(size)) {
+            (Some(siblings), Some(root)) => {
+                let proof = Proof {
+                    algorithm: root.algorithm,
+                    mode: root.mode,
+                    leaf_index: index,
+                    leaf_count: size,
+                    siblings,
+                };
+                let response = proof_response(&proof, encoding, &root.hash);
+                (response, Some(root))
+            }
+            _ => {
+                let response = HttpResponse::NotFound().body(format!(
+                    "File index {} is out of range of {} files, there are {} files",
+                    index,
+                    size,
+                    mmr.leaf_count()
+                ));
+                (response, None)
+            }
+        }
+    })?;
+    match root {
+        Some(root) => with_receipt(dataset, response, &root),
+        None => Ok(response),
+    }
+}
+
+// The merkle root of the first `size` files.
+#[get("/mmr/root")]
+async fn download_mmr_root(
+    params: web::Query<SizeParams>,
+    dataset: DatasetRef,
+) -> Result<HttpResponse> {
+    with_mmr(&dataset, |mmr| {
+        let size = params.size.unwrap_or(mmr.leaf_count());
+        match mmr.root(size) {
+            Some(root) => HttpResponse::Ok().body(root.to_string()),
+            None => HttpResponse::NotFound().body(format!(
+                "There are only {} files, fewer than {}",
+                mmr.leaf_count(),
+                size
+            )),
+        }
+    })
+}
+
+#[get("/multiproof")]
+async fn download_multiproof(
+    params: web::Query<MultiproofParams>,
+    dataset: DatasetRef,
+) -> Result<HttpResponse> {
+    let indices: Vec<usize> = match params.indices.split(',').map(str::parse).collect() {
+        Ok(indices) => indices,
+        Err(_) => {
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "Invalid indices. Must be comma separated file indices, but got: {}",
+                params.indices
+            )));
+        }
+    };
+    println!("Downloading multiproof for {} files", indices.len());
+    let (response, root) = with_mapped_tree(&dataset, |tree| {
+        let root = tree.root();
+        if let Some(index) = indices.iter().find(|&&index| index >= root.leaf_count) {
+            let response = HttpResponse::NotFound().body(format!(
+                "File index {} is out of range, there are {} files",
+                index, root.leaf_count
+            ));
+            return (response, root);
+        }
+        let proof = tree.make_multiproof(&indices).unwrap();
+        let flattened: Vec<u8> = proof.into_iter().flatten().collect();
+        (HttpResponse::Ok().body(flattened), root)
+    })?;
+    with_receipt(&dataset, response, &root)
+}
+
+// End the upload: the tree file of the uploaded files is written
+// and the root is sent back signed, for the client to check against the root it calculated.
+// The receipt is kept and attached to the proofs against the root from then on.
+#[post("/upload/finalize")]
+async fn finalize_upload(dataset: DatasetRef, key: ServerKey) -> Result<HttpResponse> {
+    let receipt = with_mapped_tree(&dataset, |tree| {
+        Receipt::sign(&dataset.id, tree.root(), &key)
+    })?;
+    std::fs::create_dir_all(dataset.path("receipts"))?;
+    std::fs::write(
+        dataset
+            .path("receipts")
+            .join(receipt.leaf_count.to_string()),
+        serde_json::to_vec(&receipt)?,
+    )?;
+    println!(
+        "Upload finalized with {} files: {}",
+        receipt.leaf_count, receipt.root
+    );
+    Ok(HttpResponse::Ok().json(receipt))
+}
+
+// The public key the server signs receipts with, in hex.
+#[get("/key")]
+async fn download_key(key: ServerKey) -> impl Responder {
+    HttpResponse::Ok().body(hex::encode(key.verifying_key().to_bytes()))
+}
+
+#[get("/root")]
+async fn download_root(dataset: DatasetRef) -> Result<HttpResponse> {
+    with_mapped_tree(&dataset, |tree| {
+        HttpResponse::Ok().body(tree.root().to_string())
+    })
+}
+
+// Proof that the tree of the first old_size files is a prefix of the current tree.
+#[get("/consistency/{old_size}")]
+async fn download_consistency_proof(
+    path: web::Path<ConsistencyPath>,
+    dataset: DatasetRef,
+) -> Result<HttpResponse> {
+    let old_size = path.old_size;
+    println!("Downloading consistency proof from {} files", old_size);
+    with_mapped_tree(&dataset, |tree| {
+        let leaf_count = tree.root().leaf_count;
+        let Some(proof) = tree.make_consistency_proof(old_size) else {
+            return HttpResponse::NotFound().body(format!(
+                "There are only {} files, fewer than {}",
+                leaf_count, old_size
+            ));
+        };
+        let flattened: Vec<u8> = proof.into_iter().flatten().collect();
+        HttpResponse::Ok().body(flattened)
+    })
+}
+
+// Open the datasets kept by the server: the default one and the ones in the datasets directory,
+// with their tree files and mountain ranges checked against their files.
+fn load_datasets() -> Result<BTreeMap<String, Arc<Dataset>>> {
+    let mut datasets = BTreeMap::new();
+    datasets.insert(
+        DEFAULT_DATASET.to_string(),
+        Arc::new(Dataset::new(DEFAULT_DATASET, PathBuf::from("."))),
+    );
+    let datasets_dir = PathBuf::from(DATASETS_DIR);
+    if datasets_dir.exists() {
+        for entry in std::fs::read_dir(datasets_dir)? {
+            let dir = entry?.path();
+            let id = dir.file_name().and_then(|s| s.to_str()).unwrap_or_default();
+            if check_dataset_id(id).is_err() || !dir.is_dir() {
+                println!("Skipping {}, not a dataset", dir.display());
+                continue;
+            }
+            datasets.insert(id.to_string(), Arc::new(Dataset::new(id, dir.clone())));
+        }
+    }
+    for dataset in datasets.values() {
+        load_tree_file(dataset)?;
+        load_mmr_file(dataset)?;
+    }
+    Ok(datasets)
+}
+
+// The ids of the datasets, one per line.
+#[get("/datasets")]
+async fn list_datasets(datasets: Datasets) -> impl Responder {
+    let ids: Vec<String> = datasets.lock().unwrap().keys().cloned().collect();
+    HttpResponse::Ok().body(ids.join("\n"))
+}
+
+// Create an empty dataset to upload files to under `/datasets/{dataset}`.
+#[post("/datasets/{dataset}")]
+async fn create_dataset(path: web::Path<String>, datasets: Datasets) -> Result<HttpResponse> {
+    let id = path.into_inner();
+    if let Err(e) = check_dataset_id(&id) {
+        return Ok(HttpResponse::BadRequest().body(e));
+    }
+    let mut datasets = datasets.lock().unwrap();
+    if datasets.contains_key(&id) {
+        return Ok(HttpResponse::Conflict().body(format!("Dataset {} already exists", id)));
+    }
+    let dir = PathBuf::from(DATASETS_DIR).join(&id);
+    std::fs::create_dir_all(&dir)?;
+    println!("Created dataset {}", id);
+    datasets.insert(id.clone(), Arc::new(Dataset::new(&id, dir)));
+    Ok(HttpResponse::Created().finish())
+}
+
+// The routes of a dataset, served for the default dataset at the root
+// and for every dataset under `/datasets/{dataset}`.
+fn dataset_routes(config: &mut web::ServiceConfig) {
+    config
+        .service(download_file)
+        .service(download_chunk)
+        .service(download_chunk_proof)
+        .service(download_entry)
+        .service(download_named_entry)
+        .service(missing_chunks)
+        .service(upload_chunk)
+        .service(download_proof)
+        .service(update_file)
+        .service(download_multiproof)
+        .service(download_root)
+        .service(download_consistency_proof)
+        .service(download_named_file)
+        .service(download_named_proof)
+        .service(download_absence_proof)
+        .service(download_mmr_proof)
+        .service(download_mmr_root)
+        .service(finalize_upload)
+        .service(download_key)
+        .route("/upload", web::post().to(upload_file))
+        .route("/algorithms", web::get().to(algorithms));
 }
 
 #[actix_web::main]
 pub async fn server(port: &str) -> std::io::Result<()> {
-    let server = HttpServer::new(|| {
+    let datasets: Datasets = web::Data::new(Mutex::new(
+        load_datasets().map_err(|e| std::io::Error::other(e.to_string()))?,
+    ));
+    let key: ServerKey = web::Data::new(load_or_generate_key("server_key")?);
+    println!(
+        "Server key: {}",
+        hex::encode(key.verifying_key().to_bytes())
+    );
+    let server = HttpServer::new(move || {
         App::new()
-            .service(download_file)
-            .service(download_proof)
-            .route("/upload", web::post().to(upload_file))
+            .app_data(datasets.clone())
+            .app_data(key.clone())
+            .service(list_datasets)
+            // before the scope of the datasets, which would take the path as its own
+            .service(create_dataset)
+            .service(web::scope("/datasets/{dataset}").configure(dataset_routes))
+            .configure(dataset_routes)
             .route("/", web::get().to(hello))
     });
     let addr = format!("0.0.0.0:{}", port);
@@ -0,0 +1,278 @@
+use crate::hasher::*;
+use crate::merkle::*;
+use crate::sparse::leaf_hash;
+use std::fmt;
+use std::str::FromStr;
+
+/// The root of a sorted-leaf Merkle tree.
+/// Formatted as `<algorithm>:<mode>:sorted:<leaf count>:<hex root>`, e.g. `sha256:rfc6962:sorted:3:9f86d0...`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortedRoot(pub MerkleRoot);
+
+impl fmt::Display for SortedRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let root = &self.0;
+        write!(
+            f,
+            "{}:{}:sorted:{}:{}",
+            root.algorithm,
+            root.mode,
+            root.leaf_count,
+            hex_hash(&root.hash)
+        )
+    }
+}
+
+impl FromStr for SortedRoot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.trim().split(':').collect();
+        let [algorithm, mode, "sorted", leaf_count, hex_root] = fields[..] else {
+            return Err(format!(
+                "Sorted merkle root must be <algorithm>:<mode>:sorted:<leaf count>:<hex root>, but got: {}",
+                s.trim()
+            ));
+        };
+        Ok(SortedRoot(
+            [algorithm, mode, leaf_count, hex_root].join(":").parse()?,
+        ))
+    }
+}
+
+/// A [`MerkleTree`] of key-value leaves ordered by key, e.g. file name hashes and file hashes.
+/// Two adjacent leaves prove that there is no key between them.
+pub struct SortedMerkleTree<H: MerkleHasher = HashAlgorithm> {
+    entries: Vec<([u8; 32], [u8; 32])>,
+    tree: MerkleTree<H>,
+}
+
+impl<H: MerkleHasher> SortedMerkleTree<H> {
+    /// Build the tree from key-value pairs in any order. The keys must be unique.
+    pub fn from_entries(
+        mut entries: Vec<([u8; 32], [u8; 32])>,
+        mode: TreeMode,
+        hasher: H,
+    ) -> Result<Self, String> {
+        entries.sort_unstable();
+        if let Some(pair) = entries.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(format!("Duplicate key {}", hex_hash(&pair[0].0)));
+        }
+        let hashes = entries
+            .iter()
+            .map(|(key, value)| leaf_hash(&hasher, mode, key, value))
+            .collect();
+        Ok(SortedMerkleTree {
+            entries,
+            tree: MerkleTree::from_hashes(hashes, mode, hasher),
+        })
+    }
+
+    pub fn root(&self) -> SortedRoot {
+        SortedRoot(self.tree.root())
+    }
+
+    /// Get the proof that the key is not in the tree, or `None` if it is.
+    pub fn make_absence_proof(&self, key: &[u8; 32]) -> Option<AbsenceProof> {
+        let position = self.entries.partition_point(|(k, _)| k < key);
+        if self.entries.get(position).map(|(k, _)| k) == Some(key) {
+            return None;
+        }
+        let neighbor = |index: usize| {
+            self.entries
+                .get(index)
+                .map(|&(key, value)| Neighbor { index, key, value })
+        };
+        let left = position.checked_sub(1).and_then(neighbor);
+        let right = neighbor(position);
+        let indices: Vec<usize> = left.iter().chain(&right).map(|n| n.index).collect();
+        let siblings = if indices.is_empty() {
+            Vec::new()
+        } else {
+            self.tree.make_merkle_multiproof(&indices)
+        };
+        Some(AbsenceProof {
+            left,
+            right,
+            siblings,
+        })
+    }
+}
+
+/// A leaf next to the place of an absent key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Neighbor {
+    pub index: usize,
+    pub key: [u8; 32],
+    pub value: [u8; 32],
+}
+
+/// Proof that a key is not in a sorted tree: the leaves right before and right after the key,
+/// if there are any, and a multiproof of both.
+/// Formatted one item per line as `left <index> <hex key> <hex value>` or `left none`,
+/// the same for `right`, followed by the siblings in hex.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AbsenceProof {
+    pub left: Option<Neighbor>,
+    pub right: Option<Neighbor>,
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl fmt::Display for AbsenceProof {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (side, neighbor) in [("left", &self.left), ("right", &self.right)] {
+            match neighbor {
+                Some(n) => writeln!(
+                    f,
+                    "{} {} {} {}",
+                    side,
+                    n.index,
+                    hex_hash(&n.key),
+                    hex_hash(&n.value)
+                )?,
+                None => writeln!(f, "{} none", side)?,
+            }
+        }
+        for sibling in &self.siblings {
+            writeln!(f, "{}", hex_hash(sibling))?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_hash(hex_hash: &str) -> Result<[u8; 32], String> {
+    let mut hash = [0u8; 32];
+    hex::decode_to_slice(hex_hash, &mut hash)
+        .map_err(|e| format!("Invalid hash {}: {}", hex_hash, e))?;
+    Ok(hash)
+}
+
+fn parse_neighbor(line: Option<&str>, side: &str) -> Result<Option<Neighbor>, String> {
+    let line = line.ok_or_else(|| format!("Missing {} neighbor", side))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        [s, "none"] if s == side => Ok(None),
+        [s, index, key, value] if s == side => Ok(Some(Neighbor {
+            index: index
+                .parse()
+                .map_err(|e| format!("Invalid index {}: {}", index, e))?,
+            key: parse_hash(key)?,
+            value: parse_hash(value)?,
+        })),
+        _ => Err(format!("Invalid {} neighbor: {}", side, line)),
+    }
+}
+
+impl FromStr for AbsenceProof {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut lines = s.lines();
+        Ok(AbsenceProof {
+            left: parse_neighbor(lines.next(), "left")?,
+            right: parse_neighbor(lines.next(), "right")?,
+            siblings: lines.map(parse_hash).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+/// Verify that the key is not in the sorted tree with the given root.
+pub fn verify_absence(
+    root: &SortedRoot,
+    key: &[u8; 32],
+    proof: &AbsenceProof,
+) -> Result<(), String> {
+    let root = &root.0;
+    match (&proof.left, &proof.right) {
+        (None, None) => {
+            if root.leaf_count != 0 || root.hash != empty_root(&root.algorithm, root.mode) {
+                return Err("Absence proof without neighbors for a non-empty tree".to_string());
+            }
+            return Ok(());
+        }
+        (Some(left), Some(right)) if right.index != left.index + 1 => {
+            return Err(format!(
+                "Neighbors {} and {} are not adjacent",
+                left.index, right.index
+            ));
+        }
+        (None, Some(right)) if right.index != 0 => {
+            return Err(format!(
+                "Right neighbor {} is not the first leaf",
+                right.index
+            ));
+        }
+        (Some(left), None) if left.index + 1 != root.leaf_count => {
+            return Err(format!("Left neighbor {} is not the last leaf", left.index));
+        }
+        _ => {}
+    }
+    if proof.left.as_ref().is_some_and(|left| left.key >= *key) {
+        return Err("Left neighbor key is not before the key".to_string());
+    }
+    if proof.right.as_ref().is_some_and(|right| right.key <= *key) {
+        return Err("Right neighbor key is not after the key".to_string());
+    }
+    let leaves: Vec<(usize, [u8; 32])> = proof
+        .left
+        .iter()
+        .chain(&proof.right)
+        .map(|n| {
+            (
+                n.index,
+                leaf_hash(&root.algorithm, root.mode, &n.key, &n.value),
+            )
+        })
+        .collect();
+    verify_files(root, &leaves, &proof.siblings)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sorted::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn absence_proofs_are_valid(
+            entries in prop::collection::btree_map(any::<[u8;32]>(), any::<[u8;32]>(), 0..50),
+            absent in any::<[u8;32]>(),
+        ) {
+            prop_assume!(!entries.contains_key(&absent));
+            let tree = SortedMerkleTree::from_entries(
+                entries.clone().into_iter().collect(),
+                TreeMode::Rfc6962,
+                Sha256Hasher,
+            )
+            .unwrap();
+            let root = tree.root();
+            let proof = tree.make_absence_proof(&absent).unwrap();
+            let proof: AbsenceProof = proof.to_string().parse().unwrap();
+            assert_eq!(verify_absence(&root, &absent, &proof), Ok(()));
+            for key in entries.keys() {
+                assert_eq!(tree.make_absence_proof(key), None);
+                // the proof of another key doesn't work for a present one
+                assert!(verify_absence(&root, key, &proof).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn neighbors_must_be_adjacent() {
+        let entries = (0..4u8).map(|i| ([i * 2; 32], [i; 32])).collect();
+        let tree =
+            SortedMerkleTree::from_entries(entries, TreeMode::Rfc6962, Sha256Hasher).unwrap();
+        let root = tree.root();
+        // [4; 32] is there, skipping it would hide it
+        let mut proof = tree.make_absence_proof(&[3; 32]).unwrap();
+        let next = tree.make_absence_proof(&[5; 32]).unwrap();
+        proof.right = next.right;
+        assert!(verify_absence(&root, &[3; 32], &proof).is_err());
+    }
+
+    #[test]
+    fn duplicate_keys_are_rejected() {
+        let entries = vec![([1; 32], [1; 32]), ([1; 32], [2; 32])];
+        assert!(SortedMerkleTree::from_entries(entries, TreeMode::Plain, Sha256Hasher).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use crate::hasher::*;
+use crate::merkle::hex_hash;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+// Keys are 256 bit, so is the depth of the tree.
+const DEPTH: usize = 256;
+
+/// A sparse Merkle tree root together with the hash algorithm and the mode of the tree.
+/// Formatted as `<algorithm>:<mode>:sparse:<hex root>`, e.g. `sha256:rfc6962:sparse:9f86d0...`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SparseRoot {
+    pub algorithm: HashAlgorithm,
+    pub mode: TreeMode,
+    pub hash: [u8; 32],
+}
+
+impl fmt::Display for SparseRoot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:sparse:{}",
+            self.algorithm,
+            self.mode,
+            hex_hash(&self.hash)
+        )
+    }
+}
+
+impl FromStr for SparseRoot {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.trim().split(':').collect();
+        let [algorithm, mode, "sparse", hex_root] = fields[..] else {
+            return Err(format!(
+                "Sparse merkle root must be <algorithm>:<mode>:sparse:<hex root>, but got: {}",
+                s.trim()
+            ));
+        };
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(hex_root, &mut hash)
+            .map_err(|e| format!("Invalid hex string for merkle root: {}", e))?;
+        Ok(SparseRoot {
+            algorithm: algorithm.parse()?,
+            mode: mode.parse()?,
+            hash,
+        })
+    }
+}
+
+/// The key of a file addressed by its name.
+pub fn name_key<H: MerkleHasher>(hasher: &H, name: &str) -> [u8; 32] {
+    hasher.hash_leaf(TreeMode::Plain, name.as_bytes())
+}
+
+// default_hashes[h] is the root of an empty subtree of height h.
+// An empty leaf is all zeros.
+fn default_hashes<H: MerkleHasher>(hasher: &H, mode: TreeMode) -> Vec<[u8; 32]> {
+    let mut hashes = Vec::with_capacity(DEPTH + 1);
+    hashes.push([0u8; 32]);
+    for height in 0..DEPTH {
+        hashes.push(hasher.hash_node(mode, &hashes[height], &hashes[height]));
+    }
+    hashes
+}
+
+// The bit of the key that chooses the child at the given depth, the most significant bit first.
+fn key_bit(key: &[u8; 32], depth: usize) -> bool {
+    key[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+// The first bits of the key, the ones that lead to its subtree at the given depth, the others cleared.
+fn key_prefix(key: &[u8; 32], depth: usize) -> [u8; 32] {
+    let mut prefix = [0u8; 32];
+    prefix[..depth / 8].copy_from_slice(&key[..depth / 8]);
+    if depth < DEPTH {
+        prefix[depth / 8] = key[depth / 8] & !(0xff >> (depth % 8));
+    }
+    prefix
+}
+
+// The prefix of the sibling of the subtree of the key at the given depth below the root.
+fn sibling_prefix(key: &[u8; 32], depth: usize) -> [u8; 32] {
+    let mut prefix = key_prefix(key, depth);
+    prefix[(depth - 1) / 8] ^= 0x80 >> ((depth - 1) % 8);
+    prefix
+}
+
+/// A Merkle tree with a leaf for every possible 256-bit key, e.g. the hash of a file name.
+/// Almost all the leaves are empty, so only the non-empty ones are stored,
+/// together with the roots of the non-empty subtrees, which are updated on every insert.
+/// The empty subtrees are replaced with precomputed default hashes.
+///
+/// Unlike [`crate::merkle::MerkleTree`] the position of a leaf doesn't depend on the other leaves,
+/// and a proof for an empty leaf proves that the key is not in the tree.
+pub struct SparseMerkleTree<H: MerkleHasher = HashAlgorithm> {
+    hasher: H,
+    mode: TreeMode,
+    values: BTreeMap<[u8; 32], [u8; 32]>,
+    // the roots of the non-empty subtrees by their depth and key prefix, the leaves at depth 256
+    nodes: HashMap<(usize, [u8; 32]), [u8; 32]>,
+    default_hashes: Vec<[u8; 32]>,
+}
+
+impl<H: MerkleHasher> SparseMerkleTree<H> {
+    pub fn new(mode: TreeMode, hasher: H) -> Self {
+        let default_hashes = default_hashes(&hasher, mode);
+        SparseMerkleTree {
+            hasher,
+            mode,
+            values: BTreeMap::new(),
+            nodes: HashMap::new(),
+            default_hashes,
+        }
+    }
+
+    /// Set the value, e.g. the file hash, of the leaf with the given key
+    /// and hash the 256 subtrees on its path again.
+    /// Returns the old value if the leaf wasn't empty.
+    pub fn insert(&mut self, key: [u8; 32], value: [u8; 32]) -> Option<[u8; 32]> {
+        let mut hash = leaf_hash(&self.hasher, self.mode, &key, &value);
+        self.nodes.insert((DEPTH, key), hash);
+        for depth in (0..DEPTH).rev() {
+            let sibling = self.subtree_root(depth + 1, &sibling_prefix(&key, depth + 1));
+            hash = if key_bit(&key, depth) {
+                self.hasher.hash_node(self.mode, &sibling, &hash)
+            } else {
+                self.hasher.hash_node(self.mode, &hash, &sibling)
+            };
+            self.nodes.insert((depth, key_prefix(&key, depth)), hash);
+        }
+        self.values.insert(key, value)
+    }
+
+    // The root of the subtree at the given depth with the given key prefix.
+    fn subtree_root(&self, depth: usize, prefix: &[u8; 32]) -> [u8; 32] {
+        match self.nodes.get(&(depth, *prefix)) {
+            Some(hash) => *hash,
+            None => self.default_hashes[DEPTH - depth],
+        }
+    }
+
+    pub fn root(&self) -> SparseRoot {
+        SparseRoot {
+            algorithm: self.hasher.algorithm(),
+            mode: self.mode,
+            hash: self.subtree_root(0, &[0u8; 32]),
+        }
+    }
+
+    /// Get the proof for the leaf with the given key.
+    /// It proves the value of the leaf if the key is in the tree, or that the leaf is empty otherwise.
+    pub fn make_proof(&self, key: &[u8; 32]) -> SparseProof {
+        let mut proof = SparseProof {
+            bitmap: [0u8; 32],
+            siblings: Vec::new(),
+        };
+        for depth in 0..DEPTH {
+            // empty siblings are left out, their hashes are known
+            if let Some(sibling) = self.nodes.get(&(depth + 1, sibling_prefix(key, depth + 1))) {
+                proof.bitmap[depth / 8] |= 0x80 >> (depth % 8);
+                proof.siblings.push(*sibling);
+            }
+        }
+        proof
+    }
+}
+
+/// The leaf of a key with the given value, e.g. a file name hash and the file hash.
+pub fn leaf_hash<H: MerkleHasher>(
+    hasher: &H,
+    mode: TreeMode,
+    key: &[u8; 32],
+    value: &[u8; 32],
+) -> [u8; 32] {
+    hasher.hash_leaf(mode, &[&key[..], &value[..]].concat())
+}
+
+/// A sparse Merkle tree proof: the siblings on the path from the root to the leaf.
+/// The bitmap has a bit set for every depth where the sibling is not an empty subtree,
+/// and only those siblings are kept. For n leaves that's about log2(n) of the 256 siblings.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparseProof {
+    pub bitmap: [u8; 32],
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl SparseProof {
+    /// Serialize as the bitmap followed by the siblings.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.bitmap.to_vec();
+        bytes.extend(self.siblings.iter().flatten());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < 32 || !bytes.len().is_multiple_of(32) {
+            return Err(format!("Invalid sparse proof size: {}", bytes.len()));
+        }
+        let (bitmap, siblings) = bytes.split_at(32);
+        let proof = SparseProof {
+            bitmap: bitmap.try_into().unwrap(),
+            siblings: siblings
+                .chunks(32)
+                .map(|hash| hash.try_into().unwrap())
+                .collect(),
+        };
+        let set_bits: u32 = proof.bitmap.iter().map(|byte| byte.count_ones()).sum();
+        if set_bits as usize != proof.siblings.len() {
+            return Err(format!(
+                "Sparse proof bitmap has {} siblings, but got {}",
+                set_bits,
+                proof.siblings.len()
+            ));
+        }
+        Ok(proof)
+    }
+}
+
+/// Verify that the leaf with the given key has the given value, or is empty if the value is `None`.
+pub fn verify_sparse(
+    root: &SparseRoot,
+    key: &[u8; 32],
+    value: Option<&[u8; 32]>,
+    proof: &SparseProof,
+) -> Result<(), String> {
+    let hasher = &root.algorithm;
+    let default_hashes = default_hashes(hasher, root.mode);
+    let mut siblings = proof.siblings.iter().rev();
+    let mut hash = match value {
+        Some(value) => leaf_hash(hasher, root.mode, key, value),
+        None => default_hashes[0],
+    };
+    for depth in (0..DEPTH).rev() {
+        let sibling = if proof.bitmap[depth / 8] & (0x80 >> (depth % 8)) != 0 {
+            *siblings.next().ok_or("Sparse proof is missing siblings")?
+        } else {
+            default_hashes[DEPTH - depth - 1]
+        };
+        hash = if key_bit(key, depth) {
+            hasher.hash_node(root.mode, &sibling, &hash)
+        } else {
+            hasher.hash_node(root.mode, &hash, &sibling)
+        };
+    }
+    if siblings.next().is_some() {
+        return Err("Sparse proof has too many siblings".to_string());
+    }
+    if hash != root.hash {
+        return Err(format!(
+            "Calculated merkle root {} does not match the expected {}",
+            hex_hash(&hash),
+            hex_hash(&root.hash)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sparse::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        // every key takes 256 hashes, keep the number of cases down
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn sparse_proofs_are_valid(
+            values in prop::collection::btree_map(any::<[u8;32]>(), any::<[u8;32]>(), 0..8),
+            absent in any::<[u8;32]>(),
+        ) {
+            prop_assume!(!values.contains_key(&absent));
+            let mut tree = SparseMerkleTree::new(TreeMode::Rfc6962, Sha256Hasher);
+            for (key, value) in &values {
+                tree.insert(*key, *value);
+            }
+            let root = tree.root();
+            // the cached subtrees don't depend on the order of the inserts or on replaced values
+            let mut reversed = SparseMerkleTree::new(TreeMode::Rfc6962, Sha256Hasher);
+            for (key, value) in values.iter().rev() {
+                reversed.insert(*key, [0u8; 32]);
+                reversed.insert(*key, *value);
+            }
+            assert_eq!(reversed.root(), root);
+            for (key, value) in &values {
+                let proof = tree.make_proof(key);
+                let proof = SparseProof::from_bytes(&proof.to_bytes()).unwrap();
+                assert_eq!(verify_sparse(&root, key, Some(value), &proof), Ok(()));
+                // a present key can't be passed off as absent
+                assert!(verify_sparse(&root, key, None, &proof).is_err());
+            }
+            let proof = tree.make_proof(&absent);
+            assert_eq!(verify_sparse(&root, &absent, None, &proof), Ok(()));
+            assert!(verify_sparse(&root, &absent, Some(&[0u8; 32]), &proof).is_err());
+        }
+    }
+
+    #[test]
+    fn empty_subtrees_are_compressed() {
+        let mut tree = SparseMerkleTree::new(TreeMode::Rfc6962, Sha256Hasher);
+        tree.insert([0x00; 32], [1; 32]);
+        tree.insert([0xff; 32], [2; 32]);
+        // the two keys differ at the first bit, every other sibling is empty
+        let proof = tree.make_proof(&[0x00; 32]);
+        assert_eq!(proof.siblings.len(), 1);
+        assert_eq!(proof.to_bytes().len(), 64);
+    }
+
+    #[test]
+    fn sparse_root_string_roundtrip() {
+        let tree = SparseMerkleTree::new(TreeMode::Plain, Blake3Hasher);
+        let root = tree.root();
+        assert_eq!(root.to_string().parse::<SparseRoot>(), Ok(root));
+        assert!("sha256:plain:3:00".parse::<SparseRoot>().is_err());
+    }
+}
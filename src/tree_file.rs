@@ -0,0 +1,508 @@
+use crate::hasher::*;
+use crate::merkle::*;
+use memmap2::Mmap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// The first bytes of every tree file.
+pub const MAGIC: [u8; 4] = *b"MRKT";
+/// The version of the tree file format.
+pub const VERSION: u8 = 1;
+
+// magic, version, algorithm, mode, leaf count
+const HEADER_SIZE: usize = 4 + 1 + 1 + 1 + 8;
+
+// The number of nodes of every level of a tree with the given number of leaves, from the leaves up.
+// An empty tree has a single level with the empty root.
+fn level_sizes(leaf_count: usize) -> Vec<usize> {
+    let mut sizes = vec![leaf_count.max(1)];
+    while sizes[sizes.len() - 1] > 1 {
+        sizes.push(sizes[sizes.len() - 1].div_ceil(2));
+    }
+    sizes
+}
+
+/// A [`MerkleTree`] persisted as a flat array of levels and memory-mapped,
+/// so proofs are read from the page cache instead of computed or kept in memory.
+/// Appending leaves or updating one writes a new file, with only the nodes that changed hashed again.
+///
+/// The file is, integers big-endian:
+///
+/// ```text
+/// "MRKT" | version: u8 | algorithm id: u8 | mode id: u8 | leaf count: u64
+///        | the nodes of every level from the leaves to the root, 32 bytes each
+/// ```
+pub struct MappedTree {
+    mmap: Mmap,
+    root: MerkleRoot,
+    // the index of the first node of every level
+    level_offsets: Vec<usize>,
+}
+
+// The header of the tree file of a tree with the given number of leaves.
+fn encode_header(algorithm: HashAlgorithm, mode: TreeMode, leaf_count: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + 64 * leaf_count.max(1));
+    bytes.extend_from_slice(&MAGIC);
+    bytes.push(VERSION);
+    bytes.push(algorithm.id());
+    bytes.push(mode.id());
+    bytes.extend_from_slice(&(leaf_count as u64).to_be_bytes());
+    bytes
+}
+
+// The contents of the tree file of the tree.
+fn encode_tree<H: MerkleHasher>(tree: &MerkleTree<H>) -> Vec<u8> {
+    let root = tree.root();
+    let mut bytes = encode_header(root.algorithm, root.mode, root.leaf_count);
+    for level in tree.levels() {
+        bytes.extend(level.iter().flatten());
+    }
+    bytes
+}
+
+// Write the contents of a tree file next to the path and rename it, so a tree mapped from the old file stays intact.
+fn write_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, bytes)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+// The node with the given index of the level above the level of the given size:
+// the hash of its two children, or its only child if that's the last node of an odd level, promoted.
+fn parent_node(
+    algorithm: &HashAlgorithm,
+    mode: TreeMode,
+    level_size: usize,
+    index: usize,
+    node: impl Fn(usize) -> [u8; 32],
+) -> [u8; 32] {
+    let left = 2 * index;
+    if left + 1 < level_size {
+        algorithm.hash_node(mode, &node(left), &node(left + 1))
+    } else {
+        node(left)
+    }
+}
+
+impl MappedTree {
+    /// Write the tree to the file at the given path, replacing it.
+    /// The file is written next to it and renamed, so a tree mapped from the old file stays intact.
+    pub fn write<P: AsRef<Path>, H: MerkleHasher>(path: P, tree: &MerkleTree<H>) -> io::Result<()> {
+        write_file(path.as_ref(), &encode_tree(tree))
+    }
+
+    /// Write the tree with the leaves appended to the file at the given path, replacing it, and map the new file.
+    /// The nodes left of the new leaves are copied, only the ones above the new leaves are hashed.
+    pub fn append<P: AsRef<Path>>(&self, path: P, leaves: &[[u8; 32]]) -> io::Result<MappedTree> {
+        let MerkleRoot {
+            algorithm, mode, ..
+        } = self.root;
+        let leaf_count = self.root.leaf_count + leaves.len();
+        let sizes = level_sizes(leaf_count);
+        let mut bytes = encode_header(algorithm, mode, leaf_count);
+        // every level is copied up to the first node with a new descendant, the rest is computed
+        let mut kept = self.root.leaf_count;
+        let mut computed = leaves.to_vec();
+        if leaf_count == 0 {
+            computed.push(self.root.hash);
+        }
+        for level in 0..sizes.len() {
+            if level > 0 {
+                let below = std::mem::take(&mut computed);
+                let kept_below = kept;
+                kept /= 2;
+                let node_below = |index: usize| match index.checked_sub(kept_below) {
+                    Some(index) => below[index],
+                    None => self.node(level - 1, index),
+                };
+                computed = (kept..sizes[level])
+                    .map(|index| parent_node(&algorithm, mode, sizes[level - 1], index, node_below))
+                    .collect();
+            }
+            for index in 0..kept {
+                bytes.extend_from_slice(&self.node(level, index));
+            }
+            bytes.extend(computed.iter().flatten());
+        }
+        write_file(path.as_ref(), &bytes)?;
+        MappedTree::open(path)
+    }
+
+    /// Write the tree with the leaf with the given index replaced to the file at the given path,
+    /// replacing it, and map the new file. Only the nodes on the path from the leaf to the root are hashed.
+    pub fn update_leaf<P: AsRef<Path>>(
+        &self,
+        path: P,
+        index: usize,
+        leaf: [u8; 32],
+    ) -> io::Result<MappedTree> {
+        if index >= self.root.leaf_count {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Leaf {} is out of range, there are {} leaves",
+                    index, self.root.leaf_count
+                ),
+            ));
+        }
+        let mut bytes = self.mmap.to_vec();
+        let position =
+            |level: usize, index: usize| HEADER_SIZE + (self.level_offsets[level] + index) * 32;
+        bytes[position(0, index)..position(0, index) + 32].copy_from_slice(&leaf);
+        let mut idx = index;
+        for level in 1..self.level_offsets.len() {
+            idx /= 2;
+            let node_below = |index: usize| -> [u8; 32] {
+                let start = position(level - 1, index);
+                bytes[start..start + 32].try_into().unwrap()
+            };
+            let node = parent_node(
+                &self.root.algorithm,
+                self.root.mode,
+                self.level_size(level - 1),
+                idx,
+                node_below,
+            );
+            bytes[position(level, idx)..position(level, idx) + 32].copy_from_slice(&node);
+        }
+        write_file(path.as_ref(), &bytes)?;
+        MappedTree::open(path)
+    }
+
+    /// Map the tree file at the given path, checking its header and size.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the server never writes to a tree file in place, it writes a new one and renames it,
+        // so the mapped file doesn't change while it's mapped.
+        let mmap = unsafe { Mmap::map(&file)? };
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+        if mmap.len() < HEADER_SIZE {
+            return Err(invalid(format!(
+                "Tree file of {} bytes is too short",
+                mmap.len()
+            )));
+        }
+        if mmap[..4] != MAGIC {
+            return Err(invalid("Not a tree file".to_string()));
+        }
+        if mmap[4] != VERSION {
+            return Err(invalid(format!(
+                "Unsupported tree file version {}",
+                mmap[4]
+            )));
+        }
+        let algorithm = HashAlgorithm::from_id(mmap[5])
+            .ok_or_else(|| invalid(format!("Unknown hash algorithm id {}", mmap[5])))?;
+        let mode = TreeMode::from_id(mmap[6])
+            .ok_or_else(|| invalid(format!("Unknown tree mode id {}", mmap[6])))?;
+        let leaf_count = u64::from_be_bytes(mmap[7..15].try_into().unwrap());
+        let leaf_count = usize::try_from(leaf_count)
+            .map_err(|_| invalid(format!("Leaf count {} is too large", leaf_count)))?;
+        let mut level_offsets = Vec::new();
+        let mut node_count = 0usize;
+        for size in level_sizes(leaf_count) {
+            level_offsets.push(node_count);
+            node_count = node_count.saturating_add(size);
+        }
+        let expected = node_count.saturating_mul(32).saturating_add(HEADER_SIZE);
+        if mmap.len() != expected {
+            return Err(invalid(format!(
+                "Tree file of {} leaves must have {} bytes, but has {}",
+                leaf_count,
+                expected,
+                mmap.len()
+            )));
+        }
+        let mut tree = MappedTree {
+            mmap,
+            root: MerkleRoot {
+                algorithm,
+                mode,
+                leaf_count,
+                hash: [0; 32],
+            },
+            level_offsets,
+        };
+        tree.root.hash = tree.node(tree.level_offsets.len() - 1, 0);
+        Ok(tree)
+    }
+
+    fn node(&self, level: usize, index: usize) -> [u8; 32] {
+        let start = HEADER_SIZE + (self.level_offsets[level] + index) * 32;
+        self.mmap[start..start + 32].try_into().unwrap()
+    }
+
+    fn level_size(&self, level: usize) -> usize {
+        let end = match self.level_offsets.get(level + 1) {
+            Some(&offset) => offset,
+            None => self.level_offsets[level] + 1,
+        };
+        end - self.level_offsets[level]
+    }
+
+    pub fn root(&self) -> MerkleRoot {
+        self.root
+    }
+
+    /// The leaf with the given index, or `None` if there is no such leaf.
+    pub fn leaf(&self, index: usize) -> Option<[u8; 32]> {
+        (index < self.root.leaf_count).then(|| self.node(0, index))
+    }
+
+    /// Get the leaf hashes in the index order.
+    pub fn leaves(&self) -> Vec<[u8; 32]> {
+        (0..self.root.leaf_count)
+            .map(|index| self.node(0, index))
+            .collect()
+    }
+
+    /// Check that every node is the hash of its children, so the file is the tree of its leaves.
+    /// The files the leaves are the hashes of are not read.
+    pub fn verify_nodes(&self) -> bool {
+        let MerkleRoot {
+            algorithm, mode, ..
+        } = self.root;
+        if self.root.leaf_count == 0 {
+            return self.root.hash == empty_root(&algorithm, mode);
+        }
+        (1..self.level_offsets.len()).all(|level| {
+            (0..self.level_size(level)).all(|index| {
+                let node_below = |index| self.node(level - 1, index);
+                let expected = parent_node(
+                    &algorithm,
+                    mode,
+                    self.level_size(level - 1),
+                    index,
+                    node_below,
+                );
+                self.node(level, index) == expected
+            })
+        })
+    }
+
+    /// Get the merkle proof of the leaf with the given index, the same as [`MerkleTree::make_merkle_proof`],
+    /// or `None` if there is no such leaf.
+    pub fn make_proof(&self, index: usize) -> Option<Vec<[u8; 32]>> {
+        if index >= self.root.leaf_count {
+            return None;
+        }
+        let mut proof = Vec::with_capacity(self.level_offsets.len() - 1);
+        let mut idx = index;
+        for level in 0..self.level_offsets.len() - 1 {
+            let sibling = idx ^ 1;
+            // the last node of an odd level is promoted and has no sibling
+            if sibling < self.level_size(level) {
+                proof.push(self.node(level, sibling));
+            }
+            idx /= 2;
+        }
+        Some(proof)
+    }
+
+    /// Get a single merkle proof for all the leaves with the given indices,
+    /// the same as [`MerkleTree::make_merkle_multiproof`], or `None` if any of them is out of range.
+    pub fn make_multiproof(&self, indices: &[usize]) -> Option<Vec<[u8; 32]>> {
+        let mut known = indices.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        if known.iter().any(|&index| index >= self.root.leaf_count) {
+            return None;
+        }
+        let mut proof = Vec::new();
+        for level in 0..self.level_offsets.len() - 1 {
+            let mut next_known = Vec::with_capacity(known.len());
+            let mut i = 0;
+            while i < known.len() {
+                let idx = known[i];
+                let sibling = idx ^ 1;
+                if known.get(i + 1) == Some(&sibling) {
+                    // both children are known, the parent can be computed
+                    i += 1;
+                } else if sibling < self.level_size(level) {
+                    proof.push(self.node(level, sibling));
+                }
+                next_known.push(idx / 2);
+                i += 1;
+            }
+            known = next_known;
+        }
+        Some(proof)
+    }
+
+    /// Get the RFC 6962 consistency proof that the tree of the first `old_size` leaves is a prefix of this tree,
+    /// or `None` if the tree has fewer leaves.
+    pub fn make_consistency_proof(&self, old_size: usize) -> Option<Vec<[u8; 32]>> {
+        if old_size > self.root.leaf_count {
+            return None;
+        }
+        // every subtree RFC 6962 splits a tree into is a node of the tree
+        let subtree_hash = |start: usize, end: usize| {
+            let level = (end - start).next_power_of_two().trailing_zeros() as usize;
+            self.node(level, start >> level)
+        };
+        Some(make_consistency_proof(
+            old_size,
+            self.root.leaf_count,
+            subtree_hash,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tree_file::*;
+    use proptest::prelude::*;
+
+    fn any_mode() -> impl Strategy<Value = TreeMode> {
+        prop_oneof![Just(TreeMode::Plain), Just(TreeMode::Rfc6962)]
+    }
+
+    // A temporary path for a tree file of the test with the given name.
+    fn tree_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mermade-{}-{}", name, std::process::id()))
+    }
+
+    // Write the tree to the path and map it.
+    fn mapped(path: &Path, tree: &MerkleTree<HashAlgorithm>) -> MappedTree {
+        MappedTree::write(path, tree).unwrap();
+        MappedTree::open(path).unwrap()
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+        #[test]
+        fn mapped_proofs_match_tree(
+            hashes in prop::collection::vec(any::<[u8;32]>(), 0..100),
+            indices in prop::collection::vec(any::<prop::sample::Index>(), 1..10),
+        ) {
+            let path = tree_path("tree");
+            let tree = MerkleTree::from_hashes(hashes.clone(), TreeMode::Rfc6962, HashAlgorithm::Sha256);
+            let mapped = mapped(&path, &tree);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(mapped.root(), tree.root());
+            assert!(mapped.verify_nodes());
+            assert_eq!(mapped.leaves(), tree.leaves());
+            for index in 0..hashes.len() {
+                assert_eq!(mapped.make_proof(index), Some(tree.make_merkle_proof(index)));
+            }
+            assert_eq!(mapped.make_proof(hashes.len()), None);
+            if !hashes.is_empty() {
+                let indices: Vec<usize> = indices.iter().map(|index| index.index(hashes.len())).collect();
+                assert_eq!(mapped.make_multiproof(&indices), Some(tree.make_merkle_multiproof(&indices)));
+            }
+            assert_eq!(mapped.make_multiproof(&[hashes.len()]), None);
+        }
+
+        #[test]
+        fn appending_matches_tree(
+            (hashes, old_size) in prop::collection::vec(any::<[u8;32]>(), 0..100)
+                .prop_flat_map(|hashes| {
+                    let len = hashes.len();
+                    (Just(hashes), 0..=len)
+                }),
+            mode in any_mode(),
+        ) {
+            let path = tree_path("append");
+            let old_tree = MerkleTree::from_hashes(hashes[..old_size].to_vec(), mode, HashAlgorithm::Sha256);
+            let appended = mapped(&path, &old_tree).append(&path, &hashes[old_size..]).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let tree = MerkleTree::from_hashes(hashes, mode, HashAlgorithm::Sha256);
+            assert_eq!(appended.root(), tree.root());
+            assert_eq!(bytes, encode_tree(&tree));
+        }
+
+        #[test]
+        fn updating_matches_tree(
+            (hashes, index) in prop::collection::vec(any::<[u8;32]>(), 1..100)
+                .prop_flat_map(|hashes| {
+                    let len = hashes.len();
+                    (Just(hashes), 0..len)
+                }),
+            new_hash in any::<[u8;32]>(),
+            mode in any_mode(),
+        ) {
+            let path = tree_path("update");
+            let old_tree = MerkleTree::from_hashes(hashes.clone(), mode, HashAlgorithm::Sha256);
+            let old_root = old_tree.root();
+            let old = mapped(&path, &old_tree);
+            let updated = old.update_leaf(&path, index, new_hash).unwrap();
+            let bytes = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            // the old file is still mapped as it was
+            assert_eq!(old.root(), old_root);
+            let mut new_hashes = hashes.clone();
+            new_hashes[index] = new_hash;
+            let tree = MerkleTree::from_hashes(new_hashes, mode, HashAlgorithm::Sha256);
+            assert_eq!(updated.root(), tree.root());
+            assert_eq!(bytes, encode_tree(&tree));
+            let proof = updated.make_proof(index).unwrap();
+            assert_eq!(
+                verify_update(&old_root, &updated.root(), index, (&hashes[index], &new_hash), &proof),
+                Ok(())
+            );
+            // the proof of any other leaf is no proof that only this one changed
+            if hashes.len() > 1 {
+                let other = (index + 1) % hashes.len();
+                let proof = updated.make_proof(other).unwrap();
+                assert!(
+                    verify_update(&old_root, &updated.root(), other, (&hashes[other], &hashes[other]), &proof)
+                        .is_err()
+                );
+            }
+            assert!(updated.update_leaf(&path, hashes.len(), new_hash).is_err());
+        }
+
+        #[test]
+        fn consistency_proofs_are_valid(
+            (hashes, old_size) in prop::collection::vec(any::<[u8;32]>(), 0..100)
+                .prop_flat_map(|hashes| {
+                    let len = hashes.len();
+                    (Just(hashes), 0..=len)
+                }),
+            mode in any_mode(),
+        ) {
+            let path = tree_path("consistency");
+            let old_tree = MerkleTree::from_hashes(hashes[..old_size].to_vec(), mode, HashAlgorithm::Sha256);
+            let mapped_tree = mapped(&path, &MerkleTree::from_hashes(hashes.clone(), mode, HashAlgorithm::Sha256));
+            let proof = mapped_tree.make_consistency_proof(old_size).unwrap();
+            assert_eq!(verify_consistency(&old_tree.root(), &mapped_tree.root(), &proof), Ok(()));
+            assert_eq!(mapped_tree.make_consistency_proof(hashes.len() + 1), None);
+            // rewriting any old leaf breaks the consistency
+            if old_size > 0 {
+                let mut rewritten = hashes.clone();
+                rewritten[old_size - 1][0] ^= 1;
+                let mapped_tree = mapped(&path, &MerkleTree::from_hashes(rewritten, mode, HashAlgorithm::Sha256));
+                let proof = mapped_tree.make_consistency_proof(old_size).unwrap();
+                assert!(verify_consistency(&old_tree.root(), &mapped_tree.root(), &proof).is_err());
+            }
+            std::fs::remove_file(&path).unwrap();
+        }
+    }
+
+    #[test]
+    fn damaged_tree_files_are_rejected() {
+        let path = tree_path("damaged");
+        let hashes = (0..5u8).map(|i| [i; 32]).collect();
+        let tree = MerkleTree::from_hashes(hashes, TreeMode::Plain, Blake3Hasher);
+        let bytes = encode_tree(&tree);
+        // a node short
+        std::fs::write(&path, &bytes[..bytes.len() - 32]).unwrap();
+        assert!(MappedTree::open(&path).is_err());
+        // an unknown algorithm
+        let mut unknown = bytes.clone();
+        unknown[5] = 0xff;
+        std::fs::write(&path, &unknown).unwrap();
+        assert!(MappedTree::open(&path).is_err());
+        // a changed node is found by the check of the nodes
+        let mut changed = bytes;
+        changed[HEADER_SIZE] ^= 1;
+        std::fs::write(&path, &changed).unwrap();
+        let mapped = MappedTree::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(!mapped.verify_nodes());
+    }
+}
@@ -0,0 +1,322 @@
+use crate::entry::*;
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// What to do with the symbolic links found while walking a directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Leave them out.
+    #[default]
+    Skip,
+    /// Upload what they point to under the path of the link.
+    Follow,
+    /// Upload the link itself, its content is the target.
+    Store,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SymlinkPolicy::Skip => write!(f, "skip"),
+            SymlinkPolicy::Follow => write!(f, "follow"),
+            SymlinkPolicy::Store => write!(f, "store"),
+        }
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "store" => Ok(SymlinkPolicy::Store),
+            _ => Err(format!("Unknown symlink policy: {}", s)),
+        }
+    }
+}
+
+/// Which files of a directory are uploaded.
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    /// Globs of the paths of the files to upload, all files if there are none.
+    pub include: Vec<String>,
+    /// Globs of the paths of the files and directories to leave out.
+    pub exclude: Vec<String>,
+    pub symlinks: SymlinkPolicy,
+}
+
+/// A file found by walking a directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WalkedFile {
+    /// The path in the dataset, see [`canonical_path`].
+    pub path: String,
+    /// Where the file is read from.
+    pub source: PathBuf,
+    /// The target of a stored link.
+    pub link_target: Option<String>,
+    /// Reached through a followed link, so it may be anywhere outside the walked directory.
+    pub through_link: bool,
+}
+
+impl WalkedFile {
+    /// Read the content of the file, the target of a stored link.
+    pub fn open(&self) -> io::Result<Box<dyn Read>> {
+        match &self.link_target {
+            Some(target) => Ok(Box::new(io::Cursor::new(target.clone().into_bytes()))),
+            None => Ok(Box::new(fs::File::open(&self.source)?)),
+        }
+    }
+
+    /// The entry of the file with the given content hash,
+    /// with its permissions and modification time if `with_metadata` is set. Links have neither.
+    pub fn entry(&self, with_metadata: bool, hash: [u8; 32]) -> io::Result<FileEntry> {
+        match &self.link_target {
+            Some(target) => Ok(FileEntry {
+                path: self.path.clone(),
+                size: target.len() as u64,
+                permissions: None,
+                mtime: None,
+                hash,
+                symlink: true,
+            }),
+            None => FileEntry::of_file(&self.path, &self.source, with_metadata, hash),
+        }
+    }
+}
+
+/// The path of a file in the dataset from the components of its path relative to the uploaded directory:
+/// every component in Unicode normalization form C, joined with `/`.
+/// The same file has the same path on every platform, whichever form its file system keeps names in.
+pub fn canonical_path(relative: &Path) -> Result<String, String> {
+    let mut components = Vec::new();
+    for component in relative.components() {
+        let name = component
+            .as_os_str()
+            .to_str()
+            .ok_or_else(|| format!("File name {:?} is not valid UTF-8", component))?;
+        components.push(name.nfc().collect::<String>());
+    }
+    let path = components.join("/");
+    check_path(&path)?;
+    Ok(path)
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        // `*` stays within a directory, `**` crosses them
+        let glob: Glob = GlobBuilder::new(glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| format!("Invalid glob {}: {}", glob, e))?;
+        builder.add(glob);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+struct Walker {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    symlinks: SymlinkPolicy,
+    files: Vec<WalkedFile>,
+    // the directories being walked, to stop at a link back to one of them
+    ancestors: Vec<PathBuf>,
+}
+
+impl Walker {
+    fn walk(&mut self, dir: &Path, prefix: &Path, through_link: bool) -> Result<(), String> {
+        let canonical = fs::canonicalize(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        if self.ancestors.contains(&canonical) {
+            return Err(format!("Symlink cycle at {}", dir.display()));
+        }
+        self.ancestors.push(canonical);
+        let read_dir = fs::read_dir(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+        for entry in read_dir {
+            let entry = entry.map_err(|e| format!("{}: {}", dir.display(), e))?;
+            let source = entry.path();
+            let relative = prefix.join(entry.file_name());
+            let path = canonical_path(&relative)?;
+            let error = |e: io::Error| format!("{}: {}", source.display(), e);
+            let mut file_type = entry.file_type().map_err(error)?;
+            if self.exclude.is_match(&path) {
+                continue;
+            }
+            let mut link_target = None;
+            let mut through_link = through_link;
+            if file_type.is_symlink() {
+                match self.symlinks {
+                    SymlinkPolicy::Skip => {
+                        eprintln!("Skipping symlink {}", source.display());
+                        continue;
+                    }
+                    SymlinkPolicy::Follow => {
+                        file_type = fs::metadata(&source).map_err(error)?.file_type();
+                        through_link = true;
+                    }
+                    SymlinkPolicy::Store => {
+                        let target = fs::read_link(&source).map_err(error)?;
+                        let target = target.to_str().ok_or_else(|| {
+                            format!("Symlink target {:?} is not valid UTF-8", target)
+                        })?;
+                        link_target = Some(target.to_string());
+                    }
+                }
+            }
+            if file_type.is_dir() && link_target.is_none() {
+                self.walk(&source, &relative, through_link)?;
+            } else if file_type.is_file() || link_target.is_some() {
+                if self
+                    .include
+                    .as_ref()
+                    .is_none_or(|include| include.is_match(&path))
+                {
+                    self.files.push(WalkedFile {
+                        path,
+                        source,
+                        link_target,
+                        through_link,
+                    });
+                }
+            } else {
+                eprintln!("Skipping {}, not a regular file", source.display());
+            }
+        }
+        self.ancestors.pop();
+        Ok(())
+    }
+}
+
+/// The files in the directory and all its subdirectories, in the order of their paths in the dataset,
+/// byte by byte, which is the order of their Unicode code points.
+/// Two files with the same path after normalization are an error.
+pub fn walk_dir<P: AsRef<Path>>(dir: P, options: &WalkOptions) -> Result<Vec<WalkedFile>, String> {
+    let mut walker = Walker {
+        include: match options.include.is_empty() {
+            true => None,
+            false => Some(glob_set(&options.include)?),
+        },
+        exclude: glob_set(&options.exclude)?,
+        symlinks: options.symlinks,
+        files: Vec::new(),
+        ancestors: Vec::new(),
+    };
+    walker.walk(dir.as_ref(), Path::new(""), false)?;
+    let mut files = walker.files;
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    let mut paths = HashSet::new();
+    for file in &files {
+        if !paths.insert(&file.path) {
+            return Err(format!("Several files have the path {}", file.path));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::walk::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mermade-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn paths(files: &[WalkedFile]) -> Vec<&str> {
+        files.iter().map(|file| file.path.as_str()).collect()
+    }
+
+    #[test]
+    fn walks_in_canonical_order() {
+        let dir = temp_dir("walk");
+        fs::create_dir_all(dir.join("src/bin")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        // "é" decomposed, as some file systems keep it
+        for file in [
+            "b.txt",
+            "a.txt",
+            "src/main.rs",
+            "src/bin/tool.rs",
+            "target/out",
+            "cafe\u{301}",
+        ] {
+            fs::write(dir.join(file), file).unwrap();
+        }
+        let all = walk_dir(&dir, &WalkOptions::default()).unwrap();
+        let filtered = walk_dir(
+            &dir,
+            &WalkOptions {
+                include: vec!["**/*.rs".to_string(), "*.txt".to_string()],
+                exclude: vec!["target".to_string(), "src/bin".to_string()],
+                ..WalkOptions::default()
+            },
+        );
+        // a name in both forms is the same path twice
+        fs::write(dir.join("caf\u{e9}"), "other").unwrap();
+        let duplicate = walk_dir(&dir, &WalkOptions::default());
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            paths(&all),
+            [
+                "a.txt",
+                "b.txt",
+                "caf\u{e9}",
+                "src/bin/tool.rs",
+                "src/main.rs",
+                "target/out"
+            ]
+        );
+        assert_eq!(paths(&filtered.unwrap()), ["a.txt", "b.txt", "src/main.rs"]);
+        assert!(duplicate.is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_policies() {
+        let dir = temp_dir("symlinks");
+        fs::create_dir_all(dir.join("data")).unwrap();
+        fs::write(dir.join("data/file"), "content").unwrap();
+        std::os::unix::fs::symlink("data/file", dir.join("link")).unwrap();
+        std::os::unix::fs::symlink("data", dir.join("linked_dir")).unwrap();
+        let walk = |symlinks| {
+            walk_dir(
+                &dir,
+                &WalkOptions {
+                    symlinks,
+                    ..WalkOptions::default()
+                },
+            )
+        };
+        let skipped = walk(SymlinkPolicy::Skip).unwrap();
+        let followed = walk(SymlinkPolicy::Follow).unwrap();
+        let stored = walk(SymlinkPolicy::Store).unwrap();
+        std::os::unix::fs::symlink("..", dir.join("data/parent")).unwrap();
+        let cycle = walk(SymlinkPolicy::Follow);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(paths(&skipped), ["data/file"]);
+        assert_eq!(paths(&followed), ["data/file", "link", "linked_dir/file"]);
+        let through_link: Vec<bool> = followed.iter().map(|file| file.through_link).collect();
+        assert_eq!(through_link, [false, true, true]);
+        assert_eq!(paths(&stored), ["data/file", "link", "linked_dir"]);
+        assert_eq!(stored[1].link_target.as_deref(), Some("data/file"));
+        let mut target = String::new();
+        stored[1]
+            .open()
+            .unwrap()
+            .read_to_string(&mut target)
+            .unwrap();
+        assert_eq!(target, "data/file");
+        assert!(stored[1].entry(true, [0; 32]).unwrap().symlink);
+        assert!(cycle.is_err());
+    }
+}